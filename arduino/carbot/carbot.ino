@@ -5,10 +5,23 @@ AccelStepper LeftFrontWheel(AccelStepper::DRIVER, 4, 7); // Z motor
 AccelStepper RightBackWheel(AccelStepper::DRIVER, 3, 6); // Y motor
 AccelStepper RightFrontWheel(AccelStepper::DRIVER, 2, 5); // X motor
 
+// Identity and capabilities printed by the info command, checked by the backend on connect
+const char* firmwareVersion = "1.1.0";
+const char* supportedCommands = "forward backward left right rotate_left rotate_right stop speed wheels info";
+const char* supportedFeatures = "framed battery";
+
 String command = "";
 int wheelSpeed = 1000;
 const int led = 13;
 
+// Battery telemetry is printed as "battery <volts>" this often
+const unsigned long telemetryInterval = 1000;
+unsigned long lastTelemetry = 0;
+
+// Sequence number and CRC of the last framed command, to recognise retries
+long lastSeq = -1;
+byte lastCrc = 0;
+
 void setup() {
   LeftFrontWheel.setMaxSpeed(3000);
   LeftBackWheel.setMaxSpeed(3000);
@@ -24,7 +37,7 @@ void loop() {
   while (Serial.available()) {
     char c = Serial.read();
     if (c == '\n') {
-      executeCommand(command);
+      handleLine(command);
       command = "";
     } else {
       command += c;
@@ -41,9 +54,72 @@ void loop() {
   int sensorValue = analogRead(A0);
   float voltage = sensorValue * (5.0 / 1023.0) * 3;
   digitalWrite(led, voltage < 11 ? HIGH : LOW);
+
+  if (millis() - lastTelemetry >= telemetryInterval) {
+    lastTelemetry = millis();
+    Serial.print("battery ");
+    Serial.println(voltage, 2);
+  }
+}
+
+void handleLine(String line) {
+  line.trim();
+  if (line.startsWith("#")) handleFrame(line);
+  else executeCommand(line);
 }
 
-void executeCommand(String cmd) {
+// Framed command "#<seq> <command>*<crc>", answered with "#<seq> ACK*<crc>"
+// or "#<seq> NACK <reason>*<crc>". The CRC covers everything between # and *.
+void handleFrame(String frame) {
+  int star = frame.lastIndexOf('*');
+  int space = frame.indexOf(' ');
+  if (star < 0 || space < 0 || space > star) return; // Not even a sequence number to answer to
+
+  String body = frame.substring(1, star);
+  long seq = body.substring(0, space - 1).toInt();
+  byte crc = (byte) strtol(frame.substring(star + 1).c_str(), NULL, 16);
+
+  if (crc != crc8(body)) {
+    sendReply(seq, "NACK crc");
+    return;
+  }
+  // Retry of a command whose ack got lost, don't run it twice
+  if (seq == lastSeq && crc == lastCrc) {
+    sendReply(seq, "ACK");
+    return;
+  }
+  lastSeq = seq;
+  lastCrc = crc;
+
+  const char* error = executeCommand(body.substring(space));
+  if (error == NULL) sendReply(seq, "ACK");
+  else sendReply(seq, String("NACK ") + error);
+}
+
+void sendReply(long seq, String status) {
+  String body = String(seq) + " " + status;
+  byte crc = crc8(body);
+  Serial.print("#");
+  Serial.print(body);
+  Serial.print("*");
+  if (crc < 0x10) Serial.print("0");
+  Serial.println(crc, HEX);
+}
+
+// CRC-8 with polynomial 0x07
+byte crc8(String data) {
+  byte crc = 0;
+  for (unsigned int i = 0; i < data.length(); i++) {
+    crc ^= data[i];
+    for (int b = 0; b < 8; b++) {
+      crc = (crc & 0x80) ? (crc << 1) ^ 0x07 : crc << 1;
+    }
+  }
+  return crc;
+}
+
+// Returns NULL on success, or the reason the command was rejected
+const char* executeCommand(String cmd) {
   cmd.trim(); // Remove extra spaces/newlines
 
   if (cmd == "forward") moveForward();
@@ -53,6 +129,19 @@ void executeCommand(String cmd) {
   else if (cmd == "rotate_left") rotateLeft();
   else if (cmd == "rotate_right") rotateRight();
   else if (cmd == "stop") stopMoving();
+  else if (cmd == "info") printInfo();
+  else if (cmd.startsWith("wheels")) {
+    // Individual wheel speeds computed by the backend: "wheels lf lb rf rb"
+    int lf, lb, rf, rb;
+    if (sscanf(cmd.c_str() + 6, "%d %d %d %d", &lf, &lb, &rf, &rb) == 4) {
+      setAllMotors(constrain(lf, -3000, 3000), constrain(lb, -3000, 3000),
+                   constrain(rf, -3000, 3000), constrain(rb, -3000, 3000));
+    } else {
+      Serial.print("Invalid wheels command: ");
+      Serial.println(cmd);
+      return "invalid";
+    }
+  }
   else if (cmd.startsWith("speed")) {
     int s = cmd.substring(6).toInt();
     wheelSpeed = constrain(s, 100, 3000);
@@ -61,7 +150,18 @@ void executeCommand(String cmd) {
   } else {
     Serial.print("Unknown command: ");
     Serial.println(cmd);
+    return "unknown";
   }
+  return NULL;
+}
+
+void printInfo() {
+  Serial.print("firmware carbot ");
+  Serial.println(firmwareVersion);
+  Serial.print("commands ");
+  Serial.println(supportedCommands);
+  Serial.print("features ");
+  Serial.println(supportedFeatures);
 }
 
 void moveForward() {
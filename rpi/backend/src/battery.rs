@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use axum::http::StatusCode;
+use axum::response::{Json, sse::{Event, Sse}};
+use futures_util::stream::Stream;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tokio::sync::{Mutex, Notify, broadcast};
+
+use crate::config::CONFIG;
+use crate::events::{now_ms, sse_from_broadcast};
+use crate::serial::write_line;
+
+// Telemetry line printed by the firmware about once per second
+const TELEMETRY_PREFIX: &str = "battery ";
+
+// Number of consecutive readings below the threshold before the battery counts
+// as low, so the voltage sag while accelerating doesn't trigger it
+const LOW_READINGS: usize = 3;
+
+// How far above the threshold the voltage must come back to clear the low state
+const RECOVERY_MARGIN: f32 = 0.2;
+
+pub static BATTERY: Lazy<Mutex<Battery>> = Lazy::new(|| {
+    Mutex::new(Battery {
+        history: VecDeque::new(),
+        low_voltage: CONFIG.battery_low_voltage,
+        stop_on_low: CONFIG.battery_stop_on_low,
+        low: false,
+    })
+});
+
+static BATTERY_EVENTS: Lazy<broadcast::Sender<BatteryEvent>> = Lazy::new(|| broadcast::channel(16).0);
+
+// Wakes the task sending `stop`, which can't be sent from the serial reader
+// since writing may wait for the reader
+static STOP_REQUESTED: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct BatteryReading {
+    pub timestamp_ms: u64,
+    pub voltage: f32,
+}
+
+pub struct Battery {
+    history: VecDeque<BatteryReading>,
+    low_voltage: f32,
+    stop_on_low: bool,
+    low: bool,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryEventKind {
+    /// The voltage dropped below the threshold
+    Low,
+    /// The voltage came back above it, e.g. after swapping the pack
+    Recovered,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BatteryEvent {
+    pub timestamp_ms: u64,
+    pub event: BatteryEventKind,
+    pub voltage: f32,
+    pub low_voltage: f32,
+    /// Whether `stop` was sent to the Arduino
+    pub stopped: bool,
+}
+
+impl Battery {
+    /// Stores a reading, returning an event if it changes the low state
+    fn record(&mut self, voltage: f32) -> Option<BatteryEvent> {
+        if self.history.len() >= CONFIG.battery_history_len {
+            self.history.pop_front();
+        }
+        self.history.push_back(BatteryReading { timestamp_ms: now_ms(), voltage });
+
+        let event = if !self.low {
+            let below = self.history.iter().rev().take(LOW_READINGS).filter(|r| r.voltage < self.low_voltage).count();
+            if below < LOW_READINGS {
+                return None;
+            }
+            BatteryEventKind::Low
+        } else if voltage > self.low_voltage + RECOVERY_MARGIN {
+            BatteryEventKind::Recovered
+        } else {
+            return None;
+        };
+
+        self.low = event == BatteryEventKind::Low;
+        Some(BatteryEvent {
+            timestamp_ms: now_ms(),
+            event,
+            voltage,
+            low_voltage: self.low_voltage,
+            stopped: self.low && self.stop_on_low,
+        })
+    }
+}
+
+/// Parses a battery telemetry line from the firmware, e.g. `battery 11.84`
+pub fn parse_telemetry(line: &str) -> Option<f32> {
+    line.strip_prefix(TELEMETRY_PREFIX)?.trim().parse().ok().filter(|voltage: &f32| voltage.is_finite())
+}
+
+/// Records a voltage reported by the firmware
+pub async fn record(voltage: f32) {
+    let Some(event) = BATTERY.lock().await.record(voltage) else {
+        return;
+    };
+
+    match event.event {
+        BatteryEventKind::Low => eprintln!("Battery low: {:.2} V (threshold {:.2} V)", event.voltage, event.low_voltage),
+        BatteryEventKind::Recovered => println!("Battery recovered: {:.2} V", event.voltage),
+    }
+
+    if event.stopped {
+        STOP_REQUESTED.notify_one();
+    }
+    let _ = BATTERY_EVENTS.send(event);
+}
+
+/// Starts the task stopping the robot when the battery becomes low
+pub fn spawn() {
+    tokio::spawn(async {
+        loop {
+            STOP_REQUESTED.notified().await;
+            if let Err(e) = write_line("stop").await {
+                eprintln!("Battery: failed to send stop: {}", e);
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+pub struct BatteryStatus {
+    voltage: Option<f32>,
+    timestamp_ms: Option<u64>,
+    low: bool,
+    low_voltage: f32,
+    stop_on_low: bool,
+    history: Vec<BatteryReading>,
+}
+
+pub async fn battery_status() -> Json<BatteryStatus> {
+    let battery = BATTERY.lock().await;
+    let last = battery.history.back();
+    Json(BatteryStatus {
+        voltage: last.map(|r| r.voltage),
+        timestamp_ms: last.map(|r| r.timestamp_ms),
+        low: battery.low,
+        low_voltage: battery.low_voltage,
+        stop_on_low: battery.stop_on_low,
+        history: battery.history.iter().copied().collect(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct BatterySettings {
+    low_voltage: Option<f32>,
+    stop_on_low: Option<bool>,
+}
+
+pub async fn configure_battery(Json(payload): Json<BatterySettings>) -> Result<Json<BatteryStatus>, (StatusCode, String)> {
+    {
+        let mut battery = BATTERY.lock().await;
+        if let Some(low_voltage) = payload.low_voltage {
+            if !low_voltage.is_finite() || low_voltage < 0.0 {
+                return Err((StatusCode::BAD_REQUEST, "low_voltage must be a positive number".to_string()));
+            }
+            battery.low_voltage = low_voltage;
+        }
+        if let Some(stop_on_low) = payload.stop_on_low {
+            battery.stop_on_low = stop_on_low;
+        }
+    }
+    Ok(battery_status().await)
+}
+
+pub async fn battery_events() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    sse_from_broadcast(BATTERY_EVENTS.subscribe())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOW_VOLTAGE: f32 = 11.0;
+
+    fn battery() -> Battery {
+        Battery { history: VecDeque::new(), low_voltage: LOW_VOLTAGE, stop_on_low: true, low: false }
+    }
+
+    fn event(battery: &mut Battery, voltage: f32) -> Option<BatteryEventKind> {
+        battery.record(voltage).map(|event| event.event)
+    }
+
+    #[test]
+    fn two_low_readings_are_a_sag() {
+        let mut battery = battery();
+        assert_eq!(event(&mut battery, 10.8), None);
+        assert_eq!(event(&mut battery, 10.7), None);
+        assert_eq!(event(&mut battery, 11.5), None);
+        assert_eq!(event(&mut battery, 10.8), None);
+        assert!(!battery.low);
+    }
+
+    #[test]
+    fn three_low_readings_trip_once() {
+        let mut battery = battery();
+        event(&mut battery, 10.8);
+        event(&mut battery, 10.8);
+        let trip = battery.record(10.9).unwrap();
+        assert_eq!(trip.event, BatteryEventKind::Low);
+        assert_eq!(trip.voltage, 10.9);
+        assert!(trip.stopped);
+        assert!(battery.low);
+        assert_eq!(event(&mut battery, 10.7), None);
+    }
+
+    #[test]
+    fn stays_low_until_above_the_margin() {
+        let mut battery = battery();
+        (0..LOW_READINGS).for_each(|_| { event(&mut battery, 10.8); });
+        assert_eq!(event(&mut battery, LOW_VOLTAGE + 0.1), None);
+        assert_eq!(event(&mut battery, LOW_VOLTAGE + RECOVERY_MARGIN), None);
+        assert!(battery.low);
+
+        let recovered = battery.record(LOW_VOLTAGE + RECOVERY_MARGIN + 0.1).unwrap();
+        assert_eq!(recovered.event, BatteryEventKind::Recovered);
+        assert!(!recovered.stopped);
+        assert!(!battery.low);
+
+        // Needs a full series of low readings again
+        assert_eq!(event(&mut battery, 10.8), None);
+        assert_eq!(event(&mut battery, 10.8), None);
+        assert_eq!(event(&mut battery, 10.8), Some(BatteryEventKind::Low));
+    }
+
+    #[test]
+    fn only_stops_when_enabled() {
+        let mut battery = Battery { stop_on_low: false, ..battery() };
+        (0..LOW_READINGS - 1).for_each(|_| { event(&mut battery, 10.8); });
+        assert!(!battery.record(10.8).unwrap().stopped);
+    }
+
+    #[test]
+    fn parses_telemetry_lines() {
+        assert_eq!(parse_telemetry("battery 11.84"), Some(11.84));
+        assert_eq!(parse_telemetry("battery  12 "), Some(12.0));
+        for line in ["battery", "battery ", "battery abc", "battery 11.8 V", "battery11.8", "Battery 11.8", " battery 11.8", "battery nan", "battery inf", "Speed set to: 800"] {
+            assert_eq!(parse_telemetry(line), None, "{:?}", line);
+        }
+    }
+}
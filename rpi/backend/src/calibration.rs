@@ -0,0 +1,379 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use axum::http::StatusCode;
+use axum::response::Json;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tokio::sync::{Mutex, broadcast};
+use tokio::time::Instant;
+
+use crate::command::CommandError;
+use crate::config::CONFIG;
+use crate::imu::{IMU_STREAM, ImuSample};
+
+const DEFAULT_DURATION_MS: u64 = 2000;
+const MAX_DURATION_MS: u64 = 60_000;
+
+// Fewer samples than this give a poor average
+const MIN_SAMPLES: usize = 20;
+
+// Standard deviations above which the robot doesn't count as stationary. The
+// MPU6050 noise is about 0.1 °/s and 0.01 g with the low-pass filter off.
+const MAX_GYRO_STD_DPS: f32 = 0.5;
+const MAX_ACCEL_STD_G: f32 = 0.05;
+
+// Steady turning doesn't show in the deviation, but the gyro zero-rate offset
+// is within ±20 °/s, so a larger mean rate means the robot is turning
+const MAX_GYRO_BIAS_DPS: f32 = 20.0;
+
+// Limits of a sensible calibration, the MPU6050 is factory trimmed to a few percent
+const MIN_SCALE: f32 = 0.5;
+const MAX_SCALE: f32 = 2.0;
+const MAX_OFFSET_G: f32 = 0.5;
+
+/// Corrections applied to every IMU reading by the sampler
+pub static CALIBRATION: Lazy<Mutex<Calibration>> = Lazy::new(|| Mutex::new(load()));
+
+// Only one calibration collects samples at a time
+static COLLECTING: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+// Face averages of a six-face calibration in progress
+static SIX_FACE: Lazy<Mutex<SixFace>> = Lazy::new(|| Mutex::new(SixFace::default()));
+
+/// Readings are corrected as `(accel - accel_offset) * accel_scale` and `gyro - gyro_bias`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    /// In degrees per second
+    pub gyro_bias: [f32; 3],
+    /// In g
+    pub accel_offset: [f32; 3],
+    pub accel_scale: [f32; 3],
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self { gyro_bias: [0.0; 3], accel_offset: [0.0; 3], accel_scale: [1.0; 3] }
+    }
+}
+
+impl Calibration {
+    pub fn apply(&self, sample: ImuSample) -> ImuSample {
+        let (ax, ay, az) = sample.accel;
+        let (gx, gy, gz) = sample.gyro;
+        let accel = [ax, ay, az];
+        let gyro = [gx, gy, gz];
+        let accel: [f32; 3] = std::array::from_fn(|i| (accel[i] - self.accel_offset[i]) * self.accel_scale[i]);
+        let gyro: [f32; 3] = std::array::from_fn(|i| gyro[i] - self.gyro_bias[i]);
+        ImuSample { accel: accel.into(), gyro: gyro.into(), ..sample }
+    }
+
+    // Catches faces collected in the wrong orientation, or a robot that isn't flat
+    fn is_plausible(&self) -> bool {
+        self.accel_scale.iter().all(|s| (MIN_SCALE..=MAX_SCALE).contains(s))
+            && self.accel_offset.iter().all(|o| o.abs() <= MAX_OFFSET_G)
+    }
+
+    // Recovers the raw reading from a corrected one
+    fn unapply(&self, sample: ImuSample) -> ImuSample {
+        let (ax, ay, az) = sample.accel;
+        let (gx, gy, gz) = sample.gyro;
+        let accel = [ax, ay, az];
+        let gyro = [gx, gy, gz];
+        let accel: [f32; 3] = std::array::from_fn(|i| accel[i] / self.accel_scale[i] + self.accel_offset[i]);
+        let gyro: [f32; 3] = std::array::from_fn(|i| gyro[i] + self.gyro_bias[i]);
+        ImuSample { accel: accel.into(), gyro: gyro.into(), ..sample }
+    }
+}
+
+/// Side of the robot facing up while collecting, for the six-face calibration
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Face {
+    XUp,
+    XDown,
+    YUp,
+    YDown,
+    ZUp,
+    ZDown,
+}
+
+impl Face {
+    const ALL: [Face; 6] = [Face::XUp, Face::XDown, Face::YUp, Face::YDown, Face::ZUp, Face::ZDown];
+
+    fn index(self) -> usize {
+        Face::ALL.iter().position(|&f| f == self).unwrap()
+    }
+}
+
+// Raw mean readings, by face
+#[derive(Default)]
+struct SixFace {
+    accel: [Option<[f32; 3]>; 6],
+    gyro: [Option<[f32; 3]>; 6],
+}
+
+impl SixFace {
+    fn collected(&self) -> Vec<Face> {
+        Face::ALL.into_iter().filter(|f| self.accel[f.index()].is_some()).collect()
+    }
+
+    // Each axis reads +1 g facing up and -1 g facing down once corrected,
+    // which gives its offset and scale
+    fn calibration(&self) -> Option<Calibration> {
+        let accel: Vec<[f32; 3]> = self.accel.iter().copied().collect::<Option<_>>()?;
+        let gyro: Vec<[f32; 3]> = self.gyro.iter().copied().collect::<Option<_>>()?;
+
+        let mut calibration = Calibration::default();
+        for axis in 0..3 {
+            let up = accel[2 * axis][axis];
+            let down = accel[2 * axis + 1][axis];
+            calibration.accel_offset[axis] = (up + down) / 2.0;
+            calibration.accel_scale[axis] = 2.0 / (up - down);
+            calibration.gyro_bias[axis] = gyro.iter().map(|g| g[axis]).sum::<f32>() / gyro.len() as f32;
+        }
+        Some(calibration)
+    }
+}
+
+fn calibration_path() -> PathBuf {
+    CONFIG.data_dir.join("imu_calibration.json")
+}
+
+fn load() -> Calibration {
+    let path = calibration_path();
+    match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            eprintln!("Ignoring invalid IMU calibration in {}: {}", path.display(), e);
+            Calibration::default()
+        }),
+        // Not calibrated yet
+        Err(_) => Calibration::default(),
+    }
+}
+
+fn storage_error(e: impl std::fmt::Display) -> (StatusCode, Json<CommandError>) {
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(CommandError::new("storage_error", e.to_string())))
+}
+
+// Saves the calibration and starts applying it
+async fn store(calibration: Calibration) -> Result<(), (StatusCode, Json<CommandError>)> {
+    if !calibration.is_plausible() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(CommandError::new(
+                "implausible_calibration",
+                format!("Calibration out of range, check the robot's orientation: {:?}", calibration),
+            )),
+        ));
+    }
+    tokio::fs::create_dir_all(&CONFIG.data_dir).await.map_err(storage_error)?;
+    let data = serde_json::to_vec_pretty(&calibration).map_err(storage_error)?;
+    tokio::fs::write(calibration_path(), data).await.map_err(storage_error)?;
+    *CALIBRATION.lock().await = calibration;
+    Ok(())
+}
+
+fn mean_and_std(values: impl Iterator<Item = [f32; 3]> + Clone) -> ([f32; 3], f32) {
+    let n = values.clone().count() as f32;
+    let mut mean = [0.0; 3];
+    for v in values.clone() {
+        (0..3).for_each(|i| mean[i] += v[i] / n);
+    }
+    // Largest standard deviation of the three axes
+    let std = (0..3)
+        .map(|i| (values.clone().map(|v| (v[i] - mean[i]).powi(2)).sum::<f32>() / n).sqrt())
+        .fold(0.0, f32::max);
+    (mean, std)
+}
+
+/// Mean raw accel and gyro readings over `duration`, while the robot stands still
+async fn collect(duration: Duration) -> Result<([f32; 3], [f32; 3]), (StatusCode, Json<CommandError>)> {
+    // Samples come out of the sampler corrected, this undoes it
+    let current = *CALIBRATION.lock().await;
+    let mut stream = IMU_STREAM.subscribe();
+    let deadline = Instant::now() + duration;
+    let mut samples = Vec::new();
+
+    loop {
+        match tokio::time::timeout_at(deadline, stream.recv()).await {
+            Ok(Ok(sample)) => samples.push(current.unapply(sample)),
+            Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => break,
+        }
+    }
+
+    if samples.len() < MIN_SAMPLES {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(CommandError::new("not_enough_samples", format!("Only {} IMU samples, check the IMU or collect for longer", samples.len()))),
+        ));
+    }
+
+    stationary_means(&samples).map_err(|e| (StatusCode::CONFLICT, Json(CommandError::new("not_stationary", e))))
+}
+
+// Mean accel and gyro readings, if the samples show the robot standing still
+fn stationary_means(samples: &[ImuSample]) -> Result<([f32; 3], [f32; 3]), String> {
+    let (accel, accel_std) = mean_and_std(samples.iter().map(|s| <[f32; 3]>::from(s.accel)));
+    let (gyro, gyro_std) = mean_and_std(samples.iter().map(|s| <[f32; 3]>::from(s.gyro)));
+    if accel_std > MAX_ACCEL_STD_G || gyro_std > MAX_GYRO_STD_DPS {
+        return Err(format!(
+            "The robot moved while calibrating (accel std {:.3} g, gyro std {:.2} °/s)",
+            accel_std, gyro_std
+        ));
+    }
+    if gyro.iter().any(|g| g.abs() > MAX_GYRO_BIAS_DPS) {
+        return Err(format!("The robot turned while calibrating (gyro {:.1?} °/s)", gyro));
+    }
+    Ok((accel, gyro))
+}
+
+#[derive(Serialize)]
+pub struct CalibrationStatus {
+    calibration: Calibration,
+    /// Faces collected so far for a six-face calibration
+    six_face_collected: Vec<Face>,
+}
+
+pub async fn calibration_status() -> Json<CalibrationStatus> {
+    Json(CalibrationStatus {
+        calibration: *CALIBRATION.lock().await,
+        six_face_collected: SIX_FACE.lock().await.collected(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct CalibrationRequest {
+    #[serde(default = "default_duration_ms")]
+    duration_ms: u64,
+    /// Collect one face of a six-face calibration instead of calibrating lying flat
+    face: Option<Face>,
+}
+
+fn default_duration_ms() -> u64 {
+    DEFAULT_DURATION_MS
+}
+
+/// Collects samples while the robot stands still. Lying flat, this corrects the
+/// gyro bias and accel offsets, keeping the scale factors. With a `face`, the mean
+/// reading is kept until all six faces are collected, which also gives the scale
+/// factors.
+pub async fn calibrate_imu(Json(payload): Json<CalibrationRequest>) -> Result<Json<CalibrationStatus>, (StatusCode, Json<CommandError>)> {
+    if !(1..=MAX_DURATION_MS).contains(&payload.duration_ms) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(CommandError::new("invalid_duration", format!("duration_ms must be 1 to {}", MAX_DURATION_MS))),
+        ));
+    }
+    let Ok(_collecting) = COLLECTING.try_lock() else {
+        return Err((StatusCode::CONFLICT, Json(CommandError::new("calibrating", "A calibration is already running".to_string()))));
+    };
+
+    let (accel, gyro) = collect(Duration::from_millis(payload.duration_ms)).await?;
+
+    match payload.face {
+        None => {
+            // Z reads 1 g lying flat, X and Y read nothing
+            let mut calibration = *CALIBRATION.lock().await;
+            calibration.gyro_bias = gyro;
+            calibration.accel_offset = [accel[0], accel[1], accel[2] - 1.0 / calibration.accel_scale[2]];
+            store(calibration).await?;
+        }
+        Some(face) => {
+            let mut six_face = SIX_FACE.lock().await;
+            six_face.accel[face.index()] = Some(accel);
+            six_face.gyro[face.index()] = Some(gyro);
+            if let Some(calibration) = six_face.calibration() {
+                // Start over either way, one of the faces is wrong if it fails
+                *six_face = SixFace::default();
+                store(calibration).await?;
+            }
+        }
+    }
+
+    Ok(calibration_status().await)
+}
+
+/// Goes back to the raw readings and drops a six-face calibration in progress
+pub async fn reset_calibration() -> Result<Json<CalibrationStatus>, (StatusCode, Json<CommandError>)> {
+    *SIX_FACE.lock().await = SixFace::default();
+    store(Calibration::default()).await?;
+    Ok(calibration_status().await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_all_close, imu_sample};
+
+    #[test]
+    fn mean_and_largest_deviation() {
+        let values = [[0.0, 1.0, 2.0], [0.0, 3.0, 2.0], [0.0, 1.0, 2.0], [0.0, 3.0, 2.0]];
+        let (mean, std) = mean_and_std(values.into_iter());
+        assert_all_close(mean, [0.0, 2.0, 2.0], 1e-6);
+        assert!((std - 1.0).abs() < 1e-6, "{}", std);
+    }
+
+    // Alternates between `a` and `b`
+    fn samples(a: ImuSample, b: ImuSample) -> Vec<ImuSample> {
+        (0..MIN_SAMPLES).map(|i| if i % 2 == 0 { a } else { b }).collect()
+    }
+
+    #[test]
+    fn standing_still_gives_the_means() {
+        let still = samples(imu_sample(0, (0.02, -0.01, 1.0), (1.2, -0.6, 0.3)), imu_sample(0, (0.04, -0.01, 1.02), (1.4, -0.4, 0.3)));
+        let (accel, gyro) = stationary_means(&still).unwrap();
+        assert_all_close(accel, [0.03, -0.01, 1.01], 1e-4);
+        assert_all_close(gyro, [1.3, -0.5, 0.3], 1e-4);
+    }
+
+    #[test]
+    fn shaking_is_not_stationary() {
+        let gyro_noise = samples(imu_sample(0, (0.0, 0.0, 1.0), (0.0, 0.0, 1.0)), imu_sample(0, (0.0, 0.0, 1.0), (0.0, 0.0, -1.0)));
+        assert!(stationary_means(&gyro_noise).is_err());
+        let accel_noise = samples(imu_sample(0, (0.0, 0.0, 1.1), (0.0, 0.0, 0.0)), imu_sample(0, (0.0, 0.0, 0.9), (0.0, 0.0, 0.0)));
+        assert!(stationary_means(&accel_noise).is_err());
+    }
+
+    #[test]
+    fn turning_steadily_is_not_stationary() {
+        let turning = imu_sample(0, (0.0, 0.0, 1.0), (0.0, 0.0, 30.0));
+        assert!(stationary_means(&samples(turning, turning)).is_err());
+    }
+
+    #[test]
+    fn six_faces_give_offset_and_scale() {
+        let mut six_face = SixFace::default();
+        for face in Face::ALL {
+            let axis = face.index() / 2;
+            let mut accel = [0.0; 3];
+            accel[axis] = if face.index() % 2 == 0 { 1.02 } else { -0.98 };
+            six_face.accel[face.index()] = Some(accel);
+            six_face.gyro[face.index()] = Some([0.5, -0.25, 1.0]);
+            assert_eq!(six_face.calibration().is_some(), face == Face::ZDown);
+        }
+
+        let calibration = six_face.calibration().unwrap();
+        assert_all_close(calibration.accel_offset, [0.02; 3], 1e-4);
+        assert_all_close(calibration.accel_scale, [1.0; 3], 1e-4);
+        assert_all_close(calibration.gyro_bias, [0.5, -0.25, 1.0], 1e-6);
+    }
+
+    #[test]
+    fn unapply_recovers_the_raw_reading() {
+        let calibration = Calibration { gyro_bias: [0.5, -0.25, 1.0], accel_offset: [0.02, -0.03, 0.05], accel_scale: [1.1, 0.95, 1.02] };
+        let raw = imu_sample(0, (0.1, -0.2, 0.98), (2.0, 3.0, -4.0));
+        let corrected = calibration.apply(raw);
+        assert_all_close(corrected.gyro.into(), [1.5, 3.25, -5.0], 1e-6);
+        let roundtrip = calibration.unapply(corrected);
+        assert_all_close(roundtrip.accel.into(), raw.accel.into(), 1e-6);
+        assert_all_close(roundtrip.gyro.into(), raw.gyro.into(), 1e-6);
+    }
+
+    #[test]
+    fn implausible_calibrations_are_caught() {
+        assert!(Calibration::default().is_plausible());
+        assert!(!Calibration { accel_scale: [1.0, 1.0, -1.0], ..Calibration::default() }.is_plausible());
+        assert!(!Calibration { accel_offset: [0.0, 0.0, 1.0], ..Calibration::default() }.is_plausible());
+    }
+}
@@ -0,0 +1,78 @@
+use image::{ImageBuffer, Rgb, RgbImage};
+
+/// Encodes a BGR8 image (3 bytes per pixel, row-major) as PNG
+pub fn encode_color_frame(width: u32, height: u32, bgr: &[u8]) -> Vec<u8> {
+    let mut img_buf = ImageBuffer::new(width, height);
+
+    for (pixel, bgr) in img_buf.pixels_mut().zip(bgr.chunks_exact(3)) {
+        *pixel = Rgb([bgr[2], bgr[1], bgr[0]]);
+    }
+
+    let mut encoded_img = Vec::new();
+    img_buf.write_to(&mut std::io::Cursor::new(&mut encoded_img), image::ImageOutputFormat::Png).unwrap();
+    encoded_img
+}
+
+fn depth_to_color(normalized: f32) -> [u8; 3] {
+    // Invert the normalized value so nearer points get higher values
+    let inverted = 1.0 - normalized;
+    let inverted = inverted.clamp(0.0, 1.0);
+
+    // Jet colormap: blue -> cyan -> green -> yellow -> red
+    let mut r = 0.0;
+    let mut g = 0.0;
+    let mut b = 0.0;
+
+    if inverted < 0.25 {
+        b = 0.5 + 2.0 * inverted;
+    } else if inverted < 0.5 {
+        b = 1.0;
+        g = -1.0 + 4.0 * inverted;
+    } else if inverted < 0.75 {
+        b = -3.0 + 4.0 * inverted;
+        g = 1.0;
+        r = -0.5 + 2.0 * inverted;
+    } else {
+        g = 1.0 - 4.0 * (inverted - 0.75);
+        r = 1.0;
+    }
+
+    [
+        (r * 255.0) as u8,
+        (g * 255.0) as u8,
+        (b * 255.0) as u8,
+    ]
+}
+
+/// Encodes Z16 depth values as a jet colored PNG, `units` converts raw values to meters
+pub fn encode_depth_frame(width: u32, height: u32, units: f32, raw_data: &[u16]) -> Vec<u8> {
+    let mut img_buf = RgbImage::new(width, height);
+
+    // Visualization range in meters (adjust based on your environment)
+    let min_m = 0.2;
+    let max_m = 5.0;
+
+    for (i, &raw_val) in raw_data.iter().enumerate() {
+        let x = i as u32 % width;
+        let y = i as u32 / width;
+
+        let dist_m = raw_val as f32 * units;
+
+        let color = if raw_val == 0 {
+            [0, 0, 0] // Black for no-data/out-of-range
+        } else {
+            // Normalize to 0.0 - 1.0 for the colormap
+            let normalized = ((dist_m - min_m) / (max_m - min_m)).clamp(0.0, 1.0);
+            depth_to_color(normalized)
+        };
+
+        img_buf.put_pixel(x, y, Rgb(color));
+    }
+
+    let mut encoded_img = Vec::new();
+    img_buf.write_to(
+        &mut std::io::Cursor::new(&mut encoded_img),
+        image::ImageOutputFormat::Png,
+    ).unwrap();
+    encoded_img
+}
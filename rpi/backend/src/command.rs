@@ -0,0 +1,235 @@
+use std::fmt;
+use std::str::FromStr;
+use axum::http::StatusCode;
+use axum::response::Json;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::mecanum::WheelSpeeds;
+use crate::serial::{Delivery, SerialError, write_line};
+
+// Range accepted by `constrain` in the firmware's speed command
+pub const MIN_SPEED: i32 = 100;
+pub const MAX_SPEED: i32 = 3000;
+
+/// Commands understood by the carbot firmware
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum MotionCommand {
+    Forward,
+    Backward,
+    Left,
+    Right,
+    RotateLeft,
+    RotateRight,
+    Stop,
+    Speed { value: i32 },
+    /// Sets each wheel speed directly, in steps per second
+    Wheels(WheelSpeeds),
+}
+
+impl MotionCommand {
+    /// Whether the wheels keep turning after this command, `None` if it doesn't change them
+    pub fn is_moving(&self) -> Option<bool> {
+        match *self {
+            MotionCommand::Stop => Some(false),
+            MotionCommand::Speed { .. } => None,
+            MotionCommand::Wheels(w) => Some(w != WheelSpeeds::default()),
+            _ => Some(true),
+        }
+    }
+
+    /// Wheel speeds the firmware sets for this command when its speed setting is
+    /// `speed`, `None` for `speed` itself, which only applies to later motions
+    pub fn wheel_speeds(&self, speed: i32) -> Option<WheelSpeeds> {
+        let s = speed;
+        let (lf, lb, rf, rb) = match *self {
+            MotionCommand::Forward => (s, s, s, s),
+            MotionCommand::Backward => (-s, -s, -s, -s),
+            MotionCommand::Left => (-s, s, s, -s),
+            MotionCommand::Right => (s, -s, -s, s),
+            MotionCommand::RotateLeft => (-s, -s, s, s),
+            MotionCommand::RotateRight => (s, s, -s, -s),
+            MotionCommand::Stop => (0, 0, 0, 0),
+            MotionCommand::Speed { .. } => return None,
+            MotionCommand::Wheels(w) => return Some(w),
+        };
+        Some(WheelSpeeds { lf, lb, rf, rb })
+    }
+
+    pub fn validate(&self) -> Result<(), CommandError> {
+        match *self {
+            MotionCommand::Speed { value } if !(MIN_SPEED..=MAX_SPEED).contains(&value) => Err(CommandError::new(
+                "speed_out_of_range",
+                format!("Speed must be between {} and {}, got {}", MIN_SPEED, MAX_SPEED, value),
+            )),
+            MotionCommand::Wheels(w) if [w.lf, w.lb, w.rf, w.rb].iter().any(|s| s.abs() > MAX_SPEED) => Err(CommandError::new(
+                "speed_out_of_range",
+                format!("Wheel speeds must be between -{} and {}", MAX_SPEED, MAX_SPEED),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Serial line sent to the firmware, without the trailing newline
+impl fmt::Display for MotionCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MotionCommand::Forward => write!(f, "forward"),
+            MotionCommand::Backward => write!(f, "backward"),
+            MotionCommand::Left => write!(f, "left"),
+            MotionCommand::Right => write!(f, "right"),
+            MotionCommand::RotateLeft => write!(f, "rotate_left"),
+            MotionCommand::RotateRight => write!(f, "rotate_right"),
+            MotionCommand::Stop => write!(f, "stop"),
+            MotionCommand::Speed { value } => write!(f, "speed {}", value),
+            MotionCommand::Wheels(w) => write!(f, "wheels {} {} {} {}", w.lf, w.lb, w.rf, w.rb),
+        }
+    }
+}
+
+// Parses a serial line as the firmware would (surrounding whitespace is ignored)
+impl FromStr for MotionCommand {
+    type Err = CommandError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        let command = match line {
+            "forward" => MotionCommand::Forward,
+            "backward" => MotionCommand::Backward,
+            "left" => MotionCommand::Left,
+            "right" => MotionCommand::Right,
+            "rotate_left" => MotionCommand::RotateLeft,
+            "rotate_right" => MotionCommand::RotateRight,
+            "stop" => MotionCommand::Stop,
+            _ => {
+                let mut words = line.split_whitespace();
+                let verb = words.next().unwrap_or("");
+                let values: Vec<i32> = words.map(|w| w.parse()).collect::<Result<_, _>>().map_err(|_| {
+                    CommandError::new("invalid_argument", format!("Invalid argument in '{}'", line))
+                })?;
+                match (verb, values.as_slice()) {
+                    ("speed", &[value]) => MotionCommand::Speed { value },
+                    ("wheels", &[lf, lb, rf, rb]) => MotionCommand::Wheels(WheelSpeeds { lf, lb, rf, rb }),
+                    ("speed" | "wheels", _) => {
+                        return Err(CommandError::new("invalid_argument", format!("Wrong number of arguments in '{}'", line)));
+                    }
+                    _ => return Err(CommandError::new("unknown_command", format!("Unknown command: '{}'", line))),
+                }
+            }
+        };
+        command.validate()?;
+        Ok(command)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct CommandError {
+    pub error: &'static str,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn new(error: &'static str, message: String) -> Self {
+        Self { error, message }
+    }
+}
+
+impl From<SerialError> for CommandError {
+    fn from(e: SerialError) -> Self {
+        Self::new(e.code(), e.to_string())
+    }
+}
+
+#[derive(Serialize)]
+pub struct CommandResponse {
+    command: MotionCommand,
+    sent: String,
+    delivery: Delivery,
+}
+
+pub async fn send_command(Json(payload): Json<Value>) -> Result<Json<CommandResponse>, (StatusCode, Json<CommandError>)> {
+    let command: MotionCommand = serde_json::from_value(payload).map_err(|e| {
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(CommandError::new("invalid_command", e.to_string())))
+    })?;
+    command.validate().map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, Json(e)))?;
+
+    let line = command.to_string();
+    let delivery = write_line(&line).await.map_err(|e| (e.status(), Json(e.into())))?;
+
+    Ok(Json(CommandResponse { command, sent: line, delivery }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_must_be_in_the_firmware_range() {
+        assert!(MotionCommand::Speed { value: MIN_SPEED }.validate().is_ok());
+        assert!(MotionCommand::Speed { value: MAX_SPEED }.validate().is_ok());
+        assert_eq!(MotionCommand::Speed { value: MIN_SPEED - 1 }.validate().unwrap_err().error, "speed_out_of_range");
+        assert_eq!(MotionCommand::Speed { value: MAX_SPEED + 1 }.validate().unwrap_err().error, "speed_out_of_range");
+    }
+
+    #[test]
+    fn wheel_speeds_are_limited_in_both_directions() {
+        let wheels = |lf| MotionCommand::Wheels(WheelSpeeds { lf, lb: 0, rf: 0, rb: 0 });
+        assert!(wheels(MAX_SPEED).validate().is_ok());
+        assert!(wheels(-MAX_SPEED).validate().is_ok());
+        assert!(wheels(0).validate().is_ok());
+        assert!(wheels(MAX_SPEED + 1).validate().is_err());
+        assert!(wheels(-MAX_SPEED - 1).validate().is_err());
+    }
+
+    #[test]
+    fn motions_without_arguments_are_always_valid() {
+        for command in [MotionCommand::Forward, MotionCommand::RotateLeft, MotionCommand::Stop] {
+            assert!(command.validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn parses_lines_as_the_firmware_does() {
+        assert_eq!("forward".parse::<MotionCommand>().unwrap(), MotionCommand::Forward);
+        assert_eq!("  rotate_right\r\n".parse::<MotionCommand>().unwrap(), MotionCommand::RotateRight);
+        assert_eq!("speed 800".parse::<MotionCommand>().unwrap(), MotionCommand::Speed { value: 800 });
+        assert_eq!(
+            "wheels 100 -200 300 -400".parse::<MotionCommand>().unwrap(),
+            MotionCommand::Wheels(WheelSpeeds { lf: 100, lb: -200, rf: 300, rb: -400 })
+        );
+    }
+
+    #[test]
+    fn parsing_reports_what_is_wrong() {
+        let error = |line: &str| line.parse::<MotionCommand>().unwrap_err().error;
+        assert_eq!(error("jump"), "unknown_command");
+        assert_eq!(error("speed fast"), "invalid_argument");
+        assert_eq!(error("speed"), "invalid_argument");
+        assert_eq!(error("wheels 1 2 3"), "invalid_argument");
+        assert_eq!(error("speed 5000"), "speed_out_of_range");
+    }
+
+    #[test]
+    fn lines_round_trip() {
+        let commands = [
+            MotionCommand::Backward,
+            MotionCommand::Left,
+            MotionCommand::Stop,
+            MotionCommand::Speed { value: 1200 },
+            MotionCommand::Wheels(WheelSpeeds { lf: -3000, lb: 0, rf: 15, rb: 3000 }),
+        ];
+        for command in commands {
+            assert_eq!(command.to_string().parse::<MotionCommand>().unwrap(), command);
+        }
+    }
+
+    #[test]
+    fn json_commands_use_the_command_tag() {
+        let command: MotionCommand = serde_json::from_str(r#"{"command": "speed", "value": 800}"#).unwrap();
+        assert_eq!(command, MotionCommand::Speed { value: 800 });
+        let command: MotionCommand = serde_json::from_str(r#"{"command": "rotate_left"}"#).unwrap();
+        assert_eq!(command, MotionCommand::RotateLeft);
+    }
+}
@@ -0,0 +1,94 @@
+use once_cell::sync::Lazy;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::framing::Protocol;
+use crate::hardware::{I2cAddress, ImuSettings};
+
+// Startup configuration, read once from environment variables
+pub static CONFIG: Lazy<Config> = Lazy::new(Config::from_env);
+
+pub struct Config {
+    /// Use simulated camera, IMU and motor link instead of the real hardware
+    pub simulation: bool,
+    /// Resolution and frame rate of the color and depth streams
+    pub camera_width: u32,
+    pub camera_height: u32,
+    pub camera_fps: u32,
+    /// Start the virtual Arduino on a pseudo-terminal at startup
+    pub virtual_arduino: bool,
+    /// Send `stop` when no command or heartbeat arrives for this long while moving
+    pub watchdog_enabled: bool,
+    pub watchdog_timeout_ms: u64,
+    /// Number of lines printed by the Arduino kept for `/serial_log`
+    pub serial_history_lines: usize,
+    /// Protocol used on connections that don't choose one (`line` or `framed`)
+    pub serial_protocol: Protocol,
+    /// Battery voltage below which the pack counts as low (3.5 V per cell for a 3S pack)
+    pub battery_low_voltage: f32,
+    /// Send `stop` when the battery becomes low
+    pub battery_stop_on_low: bool,
+    /// Number of battery readings kept for `/battery`, about one per second
+    pub battery_history_len: usize,
+    /// Largest change of a wheel speed, in steps per second squared; 0 applies speed changes at once
+    pub acceleration: u32,
+    /// Wheel steps per meter travelled (200 steps per revolution on 80 mm wheels)
+    pub steps_per_meter: f32,
+    /// Distance between the front and back axles, and between the left and right wheels
+    pub wheelbase_m: f32,
+    pub track_width_m: f32,
+    /// How often the IMU is read, and how much of its readings is kept
+    pub imu_rate_hz: u32,
+    pub imu_history_ms: u64,
+    /// MPU6050 bus, address, ranges and filter at startup
+    pub imu_settings: ImuSettings,
+    /// How quickly roll and pitch follow the accelerometer rather than the gyro, in seconds
+    pub orientation_time_constant_s: f32,
+    /// Directory for data kept across restarts, such as recorded routes
+    pub data_dir: PathBuf,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        Self {
+            simulation: !cfg!(feature = "hardware") || env_or("CARBOT_SIMULATION", false),
+            camera_width: env_or("CARBOT_CAMERA_WIDTH", 640),
+            camera_height: env_or("CARBOT_CAMERA_HEIGHT", 360),
+            camera_fps: env_or("CARBOT_CAMERA_FPS", 15),
+            virtual_arduino: env_or("CARBOT_VIRTUAL_ARDUINO", false),
+            watchdog_enabled: env_or("CARBOT_WATCHDOG", true),
+            watchdog_timeout_ms: env_or("CARBOT_WATCHDOG_TIMEOUT_MS", 1000),
+            serial_history_lines: env_or("CARBOT_SERIAL_HISTORY_LINES", 500),
+            serial_protocol: env_or("CARBOT_SERIAL_PROTOCOL", Protocol::Line),
+            battery_low_voltage: env_or("CARBOT_BATTERY_LOW_VOLTAGE", 10.5),
+            battery_stop_on_low: env_or("CARBOT_BATTERY_STOP_ON_LOW", false),
+            battery_history_len: env_or("CARBOT_BATTERY_HISTORY", 600),
+            acceleration: env_or("CARBOT_ACCELERATION", 4000),
+            steps_per_meter: env_or("CARBOT_STEPS_PER_METER", 796.0),
+            wheelbase_m: env_or("CARBOT_WHEELBASE_M", 0.2),
+            track_width_m: env_or("CARBOT_TRACK_WIDTH_M", 0.2),
+            imu_rate_hz: env_or("CARBOT_IMU_RATE_HZ", 200),
+            imu_history_ms: env_or("CARBOT_IMU_HISTORY_MS", 10_000),
+            imu_settings: ImuSettings {
+                i2c_bus: env_or("CARBOT_IMU_I2C_BUS", "/dev/i2c-1".to_string()), // Default I2C bus on Raspberry Pi
+                address: env_or("CARBOT_IMU_ADDRESS", I2cAddress(0x68)),
+                accel_range_g: env_or("CARBOT_IMU_ACCEL_RANGE_G", 2),
+                gyro_range_dps: env_or("CARBOT_IMU_GYRO_RANGE_DPS", 250),
+                dlpf_hz: env_or("CARBOT_IMU_DLPF_HZ", 260),
+                sample_rate_divider: env_or("CARBOT_IMU_SAMPLE_RATE_DIVIDER", 0),
+            },
+            orientation_time_constant_s: env_or("CARBOT_ORIENTATION_TIME_CONSTANT_S", 0.5),
+            data_dir: env_or("CARBOT_DATA_DIR", PathBuf::from("data")),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            eprintln!("Invalid value for {}: '{}', using default", name, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
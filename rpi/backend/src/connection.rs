@@ -0,0 +1,288 @@
+use std::convert::Infallible;
+use std::time::Duration;
+use axum::response::{Json, sse::{Event, Sse}};
+use futures_util::stream::Stream;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serialport::{SerialPortInfo, SerialPortType};
+use tokio::sync::{Mutex, Notify, broadcast};
+
+use crate::events::{now_ms, sse_from_broadcast};
+use crate::firmware::FirmwareInfo;
+use crate::serial::{SerialSettings, is_attached, reattach};
+
+// Delay before the first reconnection attempt, doubled after each failure
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+// Delay before reconnection attempt `attempt`, counting from 1
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))).min(MAX_BACKOFF)
+}
+
+pub static CONNECTION: Lazy<Connection> = Lazy::new(|| {
+    let (sender, _) = broadcast::channel(16);
+    Connection {
+        status: Mutex::new(ConnectionStatus { state: ConnectionState::Disconnected, device: None, settings: None, firmware: None }),
+        sender,
+        lost: Notify::new(),
+    }
+});
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ConnectionState {
+    Disconnected,
+    Connected { port_path: String },
+    Reconnecting { attempt: u32 },
+}
+
+/// USB identity of the connected device, used to find it again if its path changes
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceIdentity {
+    pub port_path: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+}
+
+impl DeviceIdentity {
+    /// Looks up the USB information of the port at `port_path`, if any
+    pub fn of(port_path: &str) -> Self {
+        Self::among(port_path, serialport::available_ports().unwrap_or_default())
+    }
+
+    fn among(port_path: &str, ports: Vec<SerialPortInfo>) -> Self {
+        let usb = ports
+            .into_iter()
+            .find(|p| p.port_name == port_path)
+            .and_then(|p| match p.port_type {
+                SerialPortType::UsbPort(info) => Some(info),
+                _ => None,
+            });
+
+        Self {
+            port_path: port_path.to_string(),
+            vid: usb.as_ref().map(|info| info.vid),
+            pid: usb.as_ref().map(|info| info.pid),
+            serial_number: usb.and_then(|info| info.serial_number),
+        }
+    }
+
+    /// Current path of the device: the port with the same VID, PID and
+    /// serial number if it is a USB device, the original path otherwise
+    fn find(&self) -> Option<String> {
+        self.find_among(serialport::available_ports().unwrap_or_default())
+    }
+
+    fn find_among(&self, ports: Vec<SerialPortInfo>) -> Option<String> {
+        let (Some(vid), Some(pid)) = (self.vid, self.pid) else {
+            return Some(self.port_path.clone());
+        };
+
+        ports
+            .into_iter()
+            .find(|p| match &p.port_type {
+                SerialPortType::UsbPort(info) => {
+                    info.vid == vid && info.pid == pid && info.serial_number == self.serial_number
+                }
+                _ => false,
+            })
+            .map(|p| p.port_name)
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct ConnectionStatus {
+    #[serde(flatten)]
+    pub state: ConnectionState,
+    pub device: Option<DeviceIdentity>,
+    /// Line settings the port was opened with, reused when reconnecting
+    pub settings: Option<SerialSettings>,
+    /// Identified by the handshake, `None` if it was skipped
+    pub firmware: Option<FirmwareInfo>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ConnectionEvent {
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub state: ConnectionState,
+    pub message: String,
+}
+
+pub struct Connection {
+    status: Mutex<ConnectionStatus>,
+    sender: broadcast::Sender<ConnectionEvent>,
+    lost: Notify,
+}
+
+impl Connection {
+    async fn set_state(&self, state: ConnectionState, message: String) {
+        self.status.lock().await.state = state.clone();
+        println!("Serial connection: {}", message);
+        let _ = self.sender.send(ConnectionEvent { timestamp_ms: now_ms(), state, message });
+    }
+
+    /// Called after the port was opened on request of the user
+    pub async fn connected(&self, device: DeviceIdentity, settings: SerialSettings, firmware: Option<FirmwareInfo>) {
+        let port_path = device.port_path.clone();
+        {
+            let mut status = self.status.lock().await;
+            status.device = Some(device);
+            status.settings = Some(settings);
+            status.firmware = firmware;
+        }
+        self.set_state(ConnectionState::Connected { port_path: port_path.clone() }, format!("Connected to {}", port_path)).await;
+    }
+
+    /// Called after the port was closed on request of the user, stops reconnecting
+    pub async fn disconnected(&self) {
+        {
+            let mut status = self.status.lock().await;
+            status.device = None;
+            status.settings = None;
+            status.firmware = None;
+        }
+        self.set_state(ConnectionState::Disconnected, "Disconnected".to_string()).await;
+    }
+
+    /// Called when reading or writing failed and the port was dropped
+    pub async fn lost(&self, error: String) {
+        if !matches!(self.status.lock().await.state, ConnectionState::Connected { .. }) {
+            return;
+        }
+        self.set_state(ConnectionState::Reconnecting { attempt: 0 }, format!("Connection lost: {}", error)).await;
+        self.lost.notify_one();
+    }
+
+    pub async fn status(&self) -> ConnectionStatus {
+        self.status.lock().await.clone()
+    }
+
+    /// Settings and firmware of the current connection, `None` while disconnected
+    pub async fn link(&self) -> (Option<SerialSettings>, Option<FirmwareInfo>) {
+        let status = self.status.lock().await;
+        (status.settings, status.firmware.clone())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// Starts the task reopening the serial port after it was lost
+pub fn spawn() {
+    tokio::spawn(async {
+        loop {
+            CONNECTION.lost.notified().await;
+
+            let mut attempt = 0;
+            loop {
+                attempt += 1;
+                tokio::time::sleep(backoff(attempt)).await;
+
+                let (device, settings, identify) = {
+                    let status = CONNECTION.status.lock().await;
+                    match (&status.state, &status.device) {
+                        (ConnectionState::Reconnecting { .. }, Some(device)) => {
+                            (device.clone(), status.settings.unwrap_or_default(), status.firmware.is_some())
+                        }
+                        // Connected or disconnected by hand in the meantime
+                        _ => break,
+                    }
+                };
+
+                let result = match device.find() {
+                    Some(port_path) => reattach(&port_path, settings, identify).await.map(|firmware| (port_path, firmware)),
+                    None => Err("device not found".to_string()),
+                };
+
+                match result {
+                    Ok((port_path, firmware)) => {
+                        {
+                            let mut status = CONNECTION.status.lock().await;
+                            status.device = Some(DeviceIdentity { port_path: port_path.clone(), ..device });
+                            // The firmware may have been updated while unplugged
+                            status.firmware = firmware;
+                        }
+                        CONNECTION.set_state(ConnectionState::Connected { port_path: port_path.clone() }, format!("Reconnected to {}", port_path)).await;
+                        break;
+                    }
+                    // Connected by hand in the meantime
+                    Err(_) if is_attached().await => break,
+                    Err(e) => {
+                        CONNECTION.set_state(ConnectionState::Reconnecting { attempt }, format!("Reconnection attempt {} failed: {}", attempt, e)).await;
+                    }
+                }
+            }
+        }
+    });
+}
+
+pub async fn connection_status() -> Json<ConnectionStatus> {
+    Json(CONNECTION.status().await)
+}
+
+pub async fn connection_events() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    sse_from_broadcast(CONNECTION.subscribe())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialport::UsbPortInfo;
+
+    fn usb_port(port_name: &str, vid: u16, pid: u16, serial_number: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: port_name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: serial_number.map(str::to_string),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    fn builtin_port(port_name: &str) -> SerialPortInfo {
+        SerialPortInfo { port_name: port_name.to_string(), port_type: SerialPortType::Unknown }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let delays: Vec<u64> = (1..=8).map(|attempt| backoff(attempt).as_millis() as u64).collect();
+        assert_eq!(delays, [250, 500, 1000, 2000, 4000, 8000, 10_000, 10_000]);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn identity_of_a_usb_port() {
+        let ports = vec![builtin_port("/dev/ttyS0"), usb_port("/dev/ttyUSB0", 0x2341, 0x0043, Some("A1"))];
+        let device = DeviceIdentity::among("/dev/ttyUSB0", ports);
+        assert_eq!((device.vid, device.pid, device.serial_number.as_deref()), (Some(0x2341), Some(0x0043), Some("A1")));
+
+        let device = DeviceIdentity::among("/dev/ttyS0", vec![builtin_port("/dev/ttyS0")]);
+        assert_eq!((device.vid, device.pid, device.serial_number), (None, None, None));
+    }
+
+    #[test]
+    fn finds_the_usb_device_under_a_new_path() {
+        let device = DeviceIdentity::among("/dev/ttyUSB0", vec![usb_port("/dev/ttyUSB0", 0x2341, 0x0043, Some("A1"))]);
+        let ports = vec![
+            // Another board of the same kind
+            usb_port("/dev/ttyUSB0", 0x2341, 0x0043, Some("B2")),
+            usb_port("/dev/ttyUSB1", 0x2341, 0x0043, Some("A1")),
+        ];
+        assert_eq!(device.find_among(ports), Some("/dev/ttyUSB1".to_string()));
+        assert_eq!(device.find_among(vec![usb_port("/dev/ttyUSB0", 0x1a86, 0x7523, Some("A1"))]), None);
+        assert_eq!(device.find_among(Vec::new()), None);
+    }
+
+    #[test]
+    fn keeps_the_path_of_other_devices() {
+        let device = DeviceIdentity::among("/dev/pts/3", Vec::new());
+        assert_eq!(device.find_among(Vec::new()), Some("/dev/pts/3".to_string()));
+    }
+}
@@ -0,0 +1,41 @@
+use std::convert::Infallible;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::stream::{self, Stream};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+static STARTED: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Milliseconds since the Unix epoch, used to timestamp events
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Microseconds since the backend started, for timestamps that must not
+/// jump when the system clock is adjusted
+pub fn monotonic_us() -> u64 {
+    STARTED.elapsed().as_micros() as u64
+}
+
+/// Streams broadcast messages as JSON server-sent events
+pub fn sse_from_broadcast<T>(receiver: broadcast::Receiver<T>) -> Sse<impl Stream<Item = Result<Event, Infallible>>>
+where
+    T: Serialize + Clone + Send + 'static,
+{
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(message) => {
+                    let event = Event::default().json_data(&message).unwrap();
+                    return Some((Ok(event), receiver));
+                }
+                // Slow client, skip the messages it missed
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,201 @@
+use std::time::Duration;
+use axum::http::StatusCode;
+use axum::response::Json;
+use serde::Serialize;
+
+use crate::connection::CONNECTION;
+use crate::serial::write_bytes;
+use crate::serial_reader::{SERIAL_LINES, collect_replies};
+
+// The Arduino resets when the port is opened and ignores input while its
+// bootloader runs, so `info` is repeated until it answers
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+const HANDSHAKE_RETRY: Duration = Duration::from_millis(300);
+
+const FIRMWARE_NAME: &str = "carbot";
+
+// Commands of the carbot firmware from before the handshake was added
+const LEGACY_COMMANDS: [&str; 8] = ["forward", "backward", "left", "right", "rotate_left", "rotate_right", "stop", "speed"];
+
+// Start of the banner Grbl prints when it boots, a common firmware on the same boards
+const GRBL_BANNER: &str = "Grbl ";
+
+/// Identity and capabilities reported by the firmware in reply to `info`:
+///
+/// ```text
+/// firmware carbot 1.1.0
+/// commands forward backward left right rotate_left rotate_right stop speed wheels info
+/// features framed battery
+/// ```
+#[derive(Serialize, Clone, Debug)]
+pub struct FirmwareInfo {
+    pub name: String,
+    /// `None` for carbot firmware too old to answer `info`
+    pub version: Option<String>,
+    pub commands: Vec<String>,
+    pub features: Vec<String>,
+}
+
+impl FirmwareInfo {
+    fn legacy() -> Self {
+        Self {
+            name: FIRMWARE_NAME.to_string(),
+            version: None,
+            commands: LEGACY_COMMANDS.iter().map(|c| c.to_string()).collect(),
+            features: Vec::new(),
+        }
+    }
+
+    /// Whether the firmware understands the command with this first word
+    pub fn supports_command(&self, verb: &str) -> bool {
+        self.commands.iter().any(|c| c == verb)
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// Something other than the carbot firmware answered, e.g. a Grbl board
+    Unexpected(String),
+    NoAnswer,
+    Write(String),
+}
+
+impl HandshakeError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HandshakeError::Unexpected(_) => StatusCode::CONFLICT,
+            HandshakeError::NoAnswer => StatusCode::GATEWAY_TIMEOUT,
+            HandshakeError::Write(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::Unexpected(line) => write!(f, "Unexpected firmware, it answered '{}'", line),
+            HandshakeError::NoAnswer => write!(f, "No answer to the firmware handshake"),
+            HandshakeError::Write(e) => write!(f, "Handshake failed: {}", e),
+        }
+    }
+}
+
+/// Identifies the firmware from its reply to `info`, `None` while the reply is
+/// incomplete. Other lines are skipped, e.g. noise while the bootloader runs, so
+/// only firmware that names itself is rejected.
+fn identify(lines: &[String]) -> Option<Result<FirmwareInfo, HandshakeError>> {
+    let mut info: Option<FirmwareInfo> = None;
+
+    for line in lines {
+        let (key, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let words: Vec<String> = rest.split_whitespace().map(String::from).collect();
+        match (key, info.as_mut()) {
+            ("firmware", _) if words.first().is_some_and(|name| name == FIRMWARE_NAME) => {
+                info = Some(FirmwareInfo {
+                    name: FIRMWARE_NAME.to_string(),
+                    version: words.get(1).cloned(),
+                    commands: Vec::new(),
+                    features: Vec::new(),
+                });
+            }
+            ("commands", Some(info)) => info.commands = words,
+            // The features line comes last
+            ("features", Some(info)) => {
+                info.features = words;
+                return Some(Ok(info.clone()));
+            }
+            ("firmware", _) => return Some(Err(HandshakeError::Unexpected(line.clone()))),
+            _ if line.starts_with("Unknown command: info") => return Some(Ok(FirmwareInfo::legacy())),
+            _ if line.starts_with(GRBL_BANNER) => return Some(Err(HandshakeError::Unexpected(line.clone()))),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Asks the firmware connected to the serial port what it is
+pub async fn handshake() -> Result<FirmwareInfo, HandshakeError> {
+    let mut receiver = SERIAL_LINES.subscribe();
+    let start = tokio::time::Instant::now();
+    let mut lines = Vec::new();
+
+    while start.elapsed() < HANDSHAKE_TIMEOUT {
+        write_bytes(b"info\n", false).await.map_err(|e| HandshakeError::Write(e.to_string()))?;
+
+        lines.extend(collect_replies(&mut receiver, HANDSHAKE_RETRY).await.into_iter().map(|l| l.line));
+        if let Some(result) = identify(&lines) {
+            return result;
+        }
+    }
+
+    Err(HandshakeError::NoAnswer)
+}
+
+pub async fn firmware_info() -> Result<Json<FirmwareInfo>, (StatusCode, String)> {
+    match CONNECTION.status().await.firmware {
+        Some(info) => Ok(Json(info)),
+        None => Err((StatusCode::NOT_FOUND, "No firmware identified, connect first".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO: [&str; 3] = [
+        "firmware carbot 1.1.0",
+        "commands forward backward stop speed wheels info",
+        "features framed battery",
+    ];
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_the_info_reply() {
+        let info = identify(&lines(&INFO)).unwrap().unwrap();
+        assert_eq!(info.name, "carbot");
+        assert_eq!(info.version.as_deref(), Some("1.1.0"));
+        assert!(info.supports_command("wheels"));
+        assert!(!info.supports_command("rotate_left"));
+        assert!(info.has_feature("battery"));
+    }
+
+    #[test]
+    fn skips_noise_around_the_reply() {
+        let mut reply = lines(&["\u{0}\u{fffd}\u{fffd}", "battery 11.84", "Speed set to: 800"]);
+        reply.extend(lines(&INFO[..2]));
+        reply.push("stray".to_string());
+        reply.push(INFO[2].to_string());
+        assert_eq!(identify(&reply).unwrap().unwrap().version.as_deref(), Some("1.1.0"));
+    }
+
+    #[test]
+    fn waits_for_the_whole_reply() {
+        assert!(identify(&[]).is_none());
+        assert!(identify(&lines(&["\u{fffd}", "garbage"])).is_none());
+        assert!(identify(&lines(&INFO[..2])).is_none());
+    }
+
+    #[test]
+    fn recognizes_legacy_carbot() {
+        let info = identify(&lines(&["noise", "Unknown command: info"])).unwrap().unwrap();
+        assert_eq!(info.version, None);
+        assert!(info.supports_command("rotate_left"));
+    }
+
+    #[test]
+    fn rejects_other_firmware() {
+        for banner in ["Grbl 1.1h ['$' for help]", "firmware otherbot 2.0"] {
+            match identify(&lines(&["noise", banner])) {
+                Some(Err(HandshakeError::Unexpected(line))) => assert_eq!(line, banner),
+                other => panic!("{:?} for {:?}", other, banner),
+            }
+        }
+    }
+}
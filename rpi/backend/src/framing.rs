@@ -0,0 +1,155 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::events::now_ms;
+
+// Framed commands look like `#<seq> <command>*<crc>` and are answered with
+// `#<seq> ACK*<crc>` or `#<seq> NACK <reason>*<crc>`, where `<crc>` is the
+// CRC-8 of everything between `#` and `*` as two hex digits.
+
+/// How commands are written to the Arduino
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// Bare text lines, as understood by every firmware version
+    #[default]
+    Line,
+    /// Lines with a sequence number and CRC, acknowledged by the firmware
+    Framed,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "line" => Ok(Protocol::Line),
+            "framed" => Ok(Protocol::Framed),
+            _ => Err(format!("protocol must be line or framed, got '{}'", s)),
+        }
+    }
+}
+
+// Starts from the clock so a restarted backend doesn't reuse the sequence
+// number of the last command the firmware saw, which it would take as a retry
+static NEXT_SEQ: Lazy<AtomicU16> = Lazy::new(|| AtomicU16::new(now_ms() as u16));
+
+pub fn next_seq() -> u16 {
+    NEXT_SEQ.fetch_add(1, Ordering::Relaxed)
+}
+
+/// CRC-8 with polynomial 0x07, as computed by the firmware
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |crc, &b| {
+        (0..8).fold(crc ^ b, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 })
+    })
+}
+
+fn frame(body: &str) -> String {
+    format!("#{}*{:02X}", body, crc8(body.as_bytes()))
+}
+
+/// Splits a frame into its body, `None` if it isn't one or its CRC is wrong
+pub fn unframe(line: &str) -> Option<&str> {
+    let (body, crc) = line.trim().strip_prefix('#')?.rsplit_once('*')?;
+    let crc = u8::from_str_radix(crc, 16).ok()?;
+    (crc == crc8(body.as_bytes())).then_some(body)
+}
+
+/// Frames a command line
+pub fn encode_command(seq: u16, line: &str) -> String {
+    frame(&format!("{} {}", seq, line))
+}
+
+/// Answer of the firmware to a framed command
+#[derive(Clone, Debug, PartialEq)]
+pub enum FrameReply {
+    Ack { seq: u16 },
+    Nack { seq: u16, reason: String },
+}
+
+impl FrameReply {
+    pub fn seq(&self) -> u16 {
+        match *self {
+            FrameReply::Ack { seq } | FrameReply::Nack { seq, .. } => seq,
+        }
+    }
+}
+
+impl fmt::Display for FrameReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameReply::Ack { seq } => write!(f, "{}", frame(&format!("{} ACK", seq))),
+            FrameReply::Nack { seq, reason } => write!(f, "{}", frame(&format!("{} NACK {}", seq, reason))),
+        }
+    }
+}
+
+/// Parses an ack or nack line, `None` for anything else (including corrupted frames)
+pub fn decode_reply(line: &str) -> Option<FrameReply> {
+    let mut words = unframe(line)?.splitn(3, ' ');
+    let seq = words.next()?.parse().ok()?;
+    match (words.next()?, words.next()) {
+        ("ACK", None) => Some(FrameReply::Ack { seq }),
+        ("NACK", reason) => Some(FrameReply::Nack { seq, reason: reason.unwrap_or("").to_string() }),
+        _ => None,
+    }
+}
+
+// Acks and nacks received by the serial reader, kept out of the line history
+pub static FRAME_REPLIES: Lazy<broadcast::Sender<FrameReply>> = Lazy::new(|| broadcast::channel(64).0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc8_matches_the_firmware() {
+        // Standard check value of CRC-8 with polynomial 0x07
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc8(b""), 0);
+    }
+
+    #[test]
+    fn encodes_commands() {
+        assert_eq!(encode_command(7, "forward"), "#7 forward*6C");
+    }
+
+    #[test]
+    fn unframe_checks_the_crc() {
+        assert_eq!(unframe("#7 forward*6C\r"), Some("7 forward"));
+        assert_eq!(unframe("#7 forward*6c"), Some("7 forward"));
+        assert_eq!(unframe("#7 forwarc*6C"), None);
+        assert_eq!(unframe("#7 forward*ZZ"), None);
+        assert_eq!(unframe("7 forward*6C"), None);
+        assert_eq!(unframe("#7 forward"), None);
+    }
+
+    #[test]
+    fn decodes_replies() {
+        assert_eq!(decode_reply("#7 ACK*CE"), Some(FrameReply::Ack { seq: 7 }));
+        assert_eq!(decode_reply("#7 NACK crc*7D"), Some(FrameReply::Nack { seq: 7, reason: "crc".to_string() }));
+        // Corrupted, or not a reply
+        assert_eq!(decode_reply("#7 ACK*CF"), None);
+        assert_eq!(decode_reply("#7 forward*6C"), None);
+        assert_eq!(decode_reply("Battery: 11.8"), None);
+    }
+
+    #[test]
+    fn replies_round_trip() {
+        for reply in [FrameReply::Ack { seq: 65535 }, FrameReply::Nack { seq: 0, reason: "unknown command".to_string() }] {
+            assert_eq!(decode_reply(&reply.to_string()), Some(reply));
+        }
+    }
+
+    #[test]
+    fn parses_protocol_names() {
+        assert_eq!("Framed".parse::<Protocol>(), Ok(Protocol::Framed));
+        assert_eq!("line".parse::<Protocol>(), Ok(Protocol::Line));
+        assert!("binary".parse::<Protocol>().is_err());
+    }
+}
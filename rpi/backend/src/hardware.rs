@@ -0,0 +1,231 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serialport::SerialPort;
+
+use crate::config::CONFIG;
+use crate::simulation::{SimulatedCamera, SimulatedImu, SimulatedMotorLink};
+
+// Port path that connects to the simulated motor link instead of a real device
+pub const SIMULATED_PORT_PATH: &str = "simulated";
+
+/// Produces pairs of PNG encoded color and depth frames
+pub trait CameraSource {
+    /// Blocks until the next pair of frames is available
+    fn next_frames(&mut self) -> Result<(Vec<u8>, Vec<u8>), String>;
+}
+
+/// Accelerometer and gyroscope readings
+pub trait Imu: Send {
+    /// In g
+    fn read_accel(&mut self) -> Result<(f32, f32, f32), Box<dyn std::error::Error>>;
+    /// In degrees per second, as published in the samples
+    fn read_gyro(&mut self) -> Result<(f32, f32, f32), Box<dyn std::error::Error>>;
+    /// Reopens the device with new settings, keeping the current ones if that fails
+    fn configure(&mut self, settings: &ImuSettings) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+// Full scales of the MPU6050, by AFS_SEL and FS_SEL value
+pub const ACCEL_RANGES_G: [u8; 4] = [2, 4, 8, 16];
+pub const GYRO_RANGES_DPS: [u16; 4] = [250, 500, 1000, 2000];
+
+// Bandwidths of the MPU6050 digital low-pass filter, by DLPF_CFG value. 260 Hz
+// is the filter turned off.
+pub const DLPF_BANDWIDTHS_HZ: [u16; 7] = [260, 184, 94, 44, 21, 10, 5];
+
+/// 7-bit I2C address, written in hex (`"0x68"`) or decimal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct I2cAddress(pub u8);
+
+impl FromStr for I2cAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+        parsed.map(I2cAddress).map_err(|_| format!("invalid I2C address '{}'", s))
+    }
+}
+
+impl fmt::Display for I2cAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#04x}", self.0)
+    }
+}
+
+impl Serialize for I2cAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for I2cAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            Number(u8),
+            Text(String),
+        }
+        match Written::deserialize(deserializer)? {
+            Written::Number(address) => Ok(I2cAddress(address)),
+            Written::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// How the MPU6050 is wired and set up
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ImuSettings {
+    pub i2c_bus: String,
+    /// 0x68, or 0x69 with AD0 pulled high
+    pub address: I2cAddress,
+    /// Full scale of the accelerometer: 2, 4, 8 or 16 g
+    pub accel_range_g: u8,
+    /// Full scale of the gyroscope: 250, 500, 1000 or 2000 °/s
+    pub gyro_range_dps: u16,
+    /// Bandwidth of the low-pass filter: 260 (off), 184, 94, 44, 21, 10 or 5 Hz
+    pub dlpf_hz: u16,
+    /// The sensor updates its readings at 1 kHz (8 kHz for the gyro with the
+    /// filter off) divided by 1 + this
+    pub sample_rate_divider: u8,
+}
+
+impl ImuSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.i2c_bus.is_empty() {
+            return Err("i2c_bus must not be empty".to_string());
+        }
+        if !(0x08..=0x77).contains(&self.address.0) {
+            return Err(format!("address must be 0x08 to 0x77, got {}", self.address));
+        }
+        if !ACCEL_RANGES_G.contains(&self.accel_range_g) {
+            return Err("accel_range_g must be 2, 4, 8 or 16".to_string());
+        }
+        if !GYRO_RANGES_DPS.contains(&self.gyro_range_dps) {
+            return Err("gyro_range_dps must be 250, 500, 1000 or 2000".to_string());
+        }
+        if !DLPF_BANDWIDTHS_HZ.contains(&self.dlpf_hz) {
+            return Err("dlpf_hz must be 260, 184, 94, 44, 21, 10 or 5".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Byte stream to the motor controller (the Arduino)
+pub trait MotorLink: Read + Write + Send {
+    /// Number of received bytes that can be read without blocking
+    fn bytes_to_read(&self) -> std::io::Result<u32>;
+}
+
+impl MotorLink for Box<dyn SerialPort> {
+    fn bytes_to_read(&self) -> std::io::Result<u32> {
+        SerialPort::bytes_to_read(self.as_ref()).map_err(std::io::Error::from)
+    }
+}
+
+pub fn open_camera() -> Result<Box<dyn CameraSource>, String> {
+    if CONFIG.simulation {
+        return Ok(Box::new(SimulatedCamera::new()));
+    }
+
+    #[cfg(feature = "hardware")]
+    {
+        let camera = crate::realsense::RealSenseCamera::new()?;
+        Ok(Box::new(camera))
+    }
+    #[cfg(not(feature = "hardware"))]
+    unreachable!("simulation is always enabled without the hardware feature")
+}
+
+pub fn open_imu(settings: &ImuSettings) -> Result<Box<dyn Imu>, Box<dyn std::error::Error>> {
+    settings.validate()?;
+    if CONFIG.simulation {
+        return Ok(Box::new(SimulatedImu::new()));
+    }
+
+    #[cfg(feature = "hardware")]
+    {
+        let mpu = crate::mpu6050::MPU6050::new(settings)?;
+        Ok(Box::new(mpu))
+    }
+    #[cfg(not(feature = "hardware"))]
+    unreachable!("simulation is always enabled without the hardware feature")
+}
+
+pub fn open_simulated_motor_link() -> Result<Box<dyn MotorLink>, String> {
+    if !CONFIG.simulation {
+        return Err("Simulated port is only available in simulation mode".to_string());
+    }
+    Ok(Box::new(SimulatedMotorLink::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> ImuSettings {
+        ImuSettings {
+            i2c_bus: "/dev/i2c-1".to_string(),
+            address: I2cAddress(0x68),
+            accel_range_g: 2,
+            gyro_range_dps: 250,
+            dlpf_hz: 260,
+            sample_rate_divider: 0,
+        }
+    }
+
+    #[test]
+    fn parses_hex_and_decimal_addresses() {
+        assert_eq!("0x68".parse(), Ok(I2cAddress(0x68)));
+        assert_eq!("0X69".parse(), Ok(I2cAddress(0x69)));
+        assert_eq!("0x0a".parse(), Ok(I2cAddress(10)));
+        assert_eq!("104".parse(), Ok(I2cAddress(104)));
+        assert_eq!(I2cAddress(0x68).to_string(), "0x68");
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for address in ["", "0x", "0x100", "256", "-1", "68h", "0x6g", " 0x68"] {
+            assert!(address.parse::<I2cAddress>().is_err(), "{:?}", address);
+        }
+    }
+
+    #[test]
+    fn accepts_every_supported_setting() {
+        assert!(settings().validate().is_ok());
+        for accel_range_g in ACCEL_RANGES_G {
+            assert!(ImuSettings { accel_range_g, ..settings() }.validate().is_ok());
+        }
+        for gyro_range_dps in GYRO_RANGES_DPS {
+            assert!(ImuSettings { gyro_range_dps, ..settings() }.validate().is_ok());
+        }
+        for dlpf_hz in DLPF_BANDWIDTHS_HZ {
+            assert!(ImuSettings { dlpf_hz, ..settings() }.validate().is_ok());
+        }
+        for address in [0x08, 0x77] {
+            assert!(ImuSettings { address: I2cAddress(address), ..settings() }.validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_settings() {
+        let invalid = [
+            ImuSettings { i2c_bus: String::new(), ..settings() },
+            ImuSettings { address: I2cAddress(0x07), ..settings() },
+            ImuSettings { address: I2cAddress(0x78), ..settings() },
+            ImuSettings { accel_range_g: 0, ..settings() },
+            ImuSettings { accel_range_g: 6, ..settings() },
+            ImuSettings { gyro_range_dps: 125, ..settings() },
+            ImuSettings { gyro_range_dps: 4000, ..settings() },
+            ImuSettings { dlpf_hz: 0, ..settings() },
+            ImuSettings { dlpf_hz: 100, ..settings() },
+        ];
+        for settings in invalid {
+            assert!(settings.validate().is_err(), "{:?}", settings);
+        }
+    }
+}
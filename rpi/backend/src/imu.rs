@@ -0,0 +1,339 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::Json;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tokio::sync::{Mutex, broadcast, oneshot};
+
+use crate::calibration::CALIBRATION;
+use crate::command::CommandError;
+use crate::config::CONFIG;
+use crate::events::{monotonic_us, now_ms};
+use crate::hardware::{I2cAddress, Imu, ImuSettings, open_imu};
+
+// Sample rates accepted for `CARBOT_IMU_RATE_HZ`
+const MIN_RATE_HZ: u32 = 1;
+const MAX_RATE_HZ: u32 = 1000;
+
+// Late samples are read right away to catch up, unless the sampler is this
+// many periods behind (e.g. the bus hung), in which case they are skipped
+const MAX_LAG_PERIODS: u32 = 10;
+
+// Default time window returned by `/imu/samples`
+const DEFAULT_WINDOW_MS: u64 = 1000;
+
+// How long `/imu/settings` waits for the sampler to reopen the IMU
+const CONFIGURE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub static SAMPLES: Lazy<Mutex<SampleBuffer>> = Lazy::new(|| {
+    let rate_hz = sample_rate_hz();
+    let capacity = (rate_hz as u64 * CONFIG.imu_history_ms / 1000).max(1) as usize;
+    Mutex::new(SampleBuffer {
+        samples: VecDeque::with_capacity(capacity),
+        capacity,
+        rate_hz,
+        errors: 0,
+        overruns: 0,
+    })
+});
+
+// Every sample as it is read, for streaming. Holds about a second at the highest rate.
+pub static IMU_STREAM: Lazy<broadcast::Sender<ImuSample>> = Lazy::new(|| broadcast::channel(1024).0);
+
+// Settings the IMU was last opened with, updated by the sampler
+static SETTINGS: Lazy<Mutex<ImuSettings>> = Lazy::new(|| Mutex::new(CONFIG.imu_settings.clone()));
+
+// New settings for the sampler to apply, since it owns the IMU
+static PENDING_SETTINGS: Lazy<Mutex<Option<PendingSettings>>> = Lazy::new(|| Mutex::new(None));
+
+// Only one change of settings at a time
+static CONFIGURING: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+struct PendingSettings {
+    settings: ImuSettings,
+    result: oneshot::Sender<Result<(), String>>,
+}
+
+/// One reading of the accelerometer (in g) and the gyroscope (in degrees per
+/// second), corrected with the calibration
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct ImuSample {
+    /// Microseconds since the backend started, from a monotonic clock
+    pub t_us: u64,
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub accel: (f32, f32, f32),
+    pub gyro: (f32, f32, f32),
+}
+
+/// Most recent samples, oldest first
+pub struct SampleBuffer {
+    samples: VecDeque<ImuSample>,
+    capacity: usize,
+    rate_hz: u32,
+    /// Failed reads
+    errors: u64,
+    /// Times the sampler fell behind and skipped samples
+    overruns: u64,
+}
+
+impl SampleBuffer {
+    fn push(&mut self, sample: ImuSample) {
+        if self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn latest(&self) -> Option<ImuSample> {
+        self.samples.back().copied()
+    }
+
+    /// Samples taken at or after `since_us`
+    pub fn since(&self, since_us: u64) -> Vec<ImuSample> {
+        // Samples are in time order, so only the tail has to be searched
+        let start = self.samples.partition_point(|s| s.t_us < since_us);
+        self.samples.range(start..).copied().collect()
+    }
+}
+
+fn sample_rate_hz() -> u32 {
+    let rate = CONFIG.imu_rate_hz;
+    if !(MIN_RATE_HZ..=MAX_RATE_HZ).contains(&rate) {
+        eprintln!("IMU sample rate must be {} to {} Hz, got {}", MIN_RATE_HZ, MAX_RATE_HZ, rate);
+    }
+    rate.clamp(MIN_RATE_HZ, MAX_RATE_HZ)
+}
+
+fn read_sample(imu: &mut dyn Imu) -> Result<ImuSample, Box<dyn std::error::Error>> {
+    let accel = imu.read_accel()?;
+    let gyro = imu.read_gyro()?;
+    Ok(ImuSample { t_us: monotonic_us(), timestamp_ms: now_ms(), accel, gyro })
+}
+
+/// Starts the thread reading the IMU at the configured rate. It runs on its own
+/// thread since the I2C reads block, and sleeps until fixed deadlines so the
+/// time spent reading doesn't add to the period. Without an IMU it only waits
+/// for new settings to open one with.
+pub fn spawn(mut imu: Option<Box<dyn Imu>>) {
+    std::thread::spawn(move || {
+        let period = Duration::from_secs_f64(1.0 / SAMPLES.blocking_lock().rate_hz as f64);
+        let mut next = Instant::now();
+        let mut failing = false;
+
+        loop {
+            let pending = PENDING_SETTINGS.blocking_lock().take();
+            if let Some(pending) = pending {
+                let result = match imu.as_mut() {
+                    Some(imu) => imu.configure(&pending.settings),
+                    None => open_imu(&pending.settings).map(|opened| imu = Some(opened)),
+                };
+                let result = result.map_err(|e| e.to_string());
+                match &result {
+                    Ok(()) => *SETTINGS.blocking_lock() = pending.settings,
+                    Err(e) => eprintln!("IMU: failed to apply settings: {}", e),
+                }
+                let _ = pending.result.send(result);
+                // Reopening takes a while, which isn't the sampler falling behind
+                next = Instant::now();
+            }
+
+            if let Some(imu) = imu.as_mut() {
+                let result = read_sample(imu.as_mut()).map(|sample| CALIBRATION.blocking_lock().apply(sample));
+                let mut buffer = SAMPLES.blocking_lock();
+                match result {
+                    Ok(sample) => {
+                        buffer.push(sample);
+                        let _ = IMU_STREAM.send(sample);
+                        if failing {
+                            println!("IMU: reading again");
+                            failing = false;
+                        }
+                    }
+                    Err(e) => {
+                        buffer.errors += 1;
+                        // Only the first error of a series, at up to 1000 reads per second
+                        if !failing {
+                            eprintln!("IMU: failed to read: {}", e);
+                            failing = true;
+                        }
+                    }
+                }
+            }
+
+            next += period;
+            let now = Instant::now();
+            if next > now {
+                std::thread::sleep(next - now);
+            } else if now - next > period * MAX_LAG_PERIODS {
+                // Too far behind to catch up, skip the missed samples
+                SAMPLES.blocking_lock().overruns += 1;
+                next = now;
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+pub struct ImuStatus {
+    rate_hz: u32,
+    /// Samples in the buffer, and how many it can hold
+    buffered: usize,
+    capacity: usize,
+    errors: u64,
+    overruns: u64,
+    latest: Option<ImuSample>,
+}
+
+pub async fn imu_status() -> Json<ImuStatus> {
+    let buffer = SAMPLES.lock().await;
+    Json(ImuStatus {
+        rate_hz: buffer.rate_hz,
+        buffered: buffer.samples.len(),
+        capacity: buffer.capacity,
+        errors: buffer.errors,
+        overruns: buffer.overruns,
+        latest: buffer.latest(),
+    })
+}
+
+/// Latest sample, also served at `/read_imu` for the frontend
+pub async fn latest_sample() -> Result<Json<ImuSample>, (StatusCode, String)> {
+    match SAMPLES.lock().await.latest() {
+        Some(sample) => Ok(Json(sample)),
+        None => Err((StatusCode::SERVICE_UNAVAILABLE, "No IMU sample yet".to_string())),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SampleWindow {
+    /// Samples from the last `duration_ms`, up to the latest one
+    duration_ms: Option<u64>,
+    /// Samples taken after this `t_us`, e.g. the last one seen, to poll for new ones
+    since_us: Option<u64>,
+}
+
+pub async fn imu_samples(Query(window): Query<SampleWindow>) -> Result<Json<Vec<ImuSample>>, (StatusCode, String)> {
+    let buffer = SAMPLES.lock().await;
+    let since_us = match (window.since_us, window.duration_ms) {
+        (Some(_), Some(_)) => return Err((StatusCode::BAD_REQUEST, "Give either since_us or duration_ms".to_string())),
+        (Some(since_us), None) => since_us.saturating_add(1),
+        (None, duration_ms) => {
+            let Some(latest) = buffer.latest() else {
+                return Ok(Json(Vec::new()));
+            };
+            latest.t_us.saturating_sub(duration_ms.unwrap_or(DEFAULT_WINDOW_MS) * 1000)
+        }
+    };
+    Ok(Json(buffer.since(since_us)))
+}
+
+pub async fn imu_settings() -> Json<ImuSettings> {
+    Json(SETTINGS.lock().await.clone())
+}
+
+/// Fields left out keep their current value
+#[derive(Deserialize)]
+pub struct ImuSettingsUpdate {
+    i2c_bus: Option<String>,
+    address: Option<I2cAddress>,
+    accel_range_g: Option<u8>,
+    gyro_range_dps: Option<u16>,
+    dlpf_hz: Option<u16>,
+    sample_rate_divider: Option<u8>,
+}
+
+/// Reopens the IMU with new settings. They last until the backend restarts,
+/// which goes back to the `CARBOT_IMU_*` variables.
+pub async fn configure_imu(Json(payload): Json<ImuSettingsUpdate>) -> Result<Json<ImuSettings>, (StatusCode, Json<CommandError>)> {
+    let _configuring = CONFIGURING.lock().await;
+
+    let mut settings = SETTINGS.lock().await.clone();
+    if let Some(i2c_bus) = payload.i2c_bus {
+        settings.i2c_bus = i2c_bus;
+    }
+    if let Some(address) = payload.address {
+        settings.address = address;
+    }
+    if let Some(accel_range_g) = payload.accel_range_g {
+        settings.accel_range_g = accel_range_g;
+    }
+    if let Some(gyro_range_dps) = payload.gyro_range_dps {
+        settings.gyro_range_dps = gyro_range_dps;
+    }
+    if let Some(dlpf_hz) = payload.dlpf_hz {
+        settings.dlpf_hz = dlpf_hz;
+    }
+    if let Some(sample_rate_divider) = payload.sample_rate_divider {
+        settings.sample_rate_divider = sample_rate_divider;
+    }
+    if let Err(e) = settings.validate() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, Json(CommandError::new("invalid_settings", e))));
+    }
+
+    let (sender, receiver) = oneshot::channel();
+    *PENDING_SETTINGS.lock().await = Some(PendingSettings { settings, result: sender });
+    match tokio::time::timeout(CONFIGURE_TIMEOUT, receiver).await {
+        Ok(Ok(Ok(()))) => Ok(imu_settings().await),
+        // The IMU still runs with the previous settings
+        Ok(Ok(Err(e))) => Err((StatusCode::BAD_GATEWAY, Json(CommandError::new("imu_error", e)))),
+        Ok(Err(_)) | Err(_) => Err((
+            StatusCode::GATEWAY_TIMEOUT,
+            Json(CommandError::new("imu_timeout", "The IMU sampler didn't apply the settings in time".to_string())),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::imu_sample;
+
+    fn buffer(capacity: usize) -> SampleBuffer {
+        SampleBuffer { samples: VecDeque::with_capacity(capacity), capacity, rate_hz: 100, errors: 0, overruns: 0 }
+    }
+
+    fn times(samples: &[ImuSample]) -> Vec<u64> {
+        samples.iter().map(|s| s.t_us).collect()
+    }
+
+    fn filled(capacity: usize, t_us: impl IntoIterator<Item = u64>) -> SampleBuffer {
+        let mut buffer = buffer(capacity);
+        for t_us in t_us {
+            buffer.push(imu_sample(t_us, (0.0, 0.0, 1.0), (0.0, 0.0, 0.0)));
+        }
+        buffer
+    }
+
+    #[test]
+    fn empty_buffer_has_no_samples() {
+        let buffer = buffer(3);
+        assert!(buffer.latest().is_none());
+        assert!(buffer.since(0).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_most_recent_samples() {
+        let buffer = filled(3, [10, 20, 30, 40, 50]);
+        assert_eq!(times(&buffer.since(0)), [30, 40, 50]);
+        assert_eq!(buffer.latest().map(|s| s.t_us), Some(50));
+        assert_eq!(buffer.samples.len(), buffer.capacity);
+    }
+
+    #[test]
+    fn since_starts_at_the_given_time() {
+        let buffer = filled(3, [10, 20, 30, 40, 50]);
+        assert_eq!(times(&buffer.since(40)), [40, 50]);
+        assert_eq!(times(&buffer.since(41)), [50]);
+        assert!(buffer.since(51).is_empty());
+    }
+
+    #[test]
+    fn since_older_than_the_buffer_returns_everything() {
+        let buffer = filled(3, [10, 20, 30, 40, 50]);
+        // The samples from 10 and 20 were dropped, the rest is all there is
+        assert_eq!(times(&buffer.since(15)), [30, 40, 50]);
+    }
+}
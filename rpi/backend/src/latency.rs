@@ -0,0 +1,139 @@
+use std::time::Duration;
+use axum::http::StatusCode;
+use axum::response::Json;
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+use crate::command::CommandError;
+use crate::serial::write_bytes;
+use crate::serial_reader::{SERIAL_LINES, SerialLine};
+
+const MAX_PROBES: u32 = 1000;
+const MAX_PROBE_TIMEOUT_MS: u64 = 5000;
+
+// Probes are numbered unknown commands, which the firmware echoes back as
+// `Unknown command: bench <n>` without doing anything else
+const PROBE_COMMAND: &str = "bench";
+
+#[derive(Deserialize)]
+pub struct BenchmarkRequest {
+    #[serde(default = "default_count")]
+    count: u32,
+    /// How long to wait for each reply before counting it as lost
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+fn default_count() -> u32 {
+    50
+}
+
+fn default_timeout_ms() -> u64 {
+    500
+}
+
+/// Round-trip times of the probes that were answered, in milliseconds
+#[derive(Serialize)]
+pub struct BenchmarkReport {
+    sent: u32,
+    lost: u32,
+    min_ms: Option<f64>,
+    mean_ms: Option<f64>,
+    p95_ms: Option<f64>,
+    max_ms: Option<f64>,
+}
+
+// Nearest-rank 95th percentile of latencies sorted in increasing order, the same
+// as `bench` in `test_arduino_serial`
+fn p95(sorted: &[f64]) -> Option<f64> {
+    (!sorted.is_empty()).then(|| sorted[(sorted.len() * 95).div_ceil(100) - 1])
+}
+
+impl BenchmarkReport {
+    fn new(sent: u32, mut latencies: Vec<f64>) -> Self {
+        latencies.sort_by(f64::total_cmp);
+        let answered = latencies.len();
+        Self {
+            sent,
+            lost: sent - answered as u32,
+            min_ms: latencies.first().copied(),
+            mean_ms: (answered > 0).then(|| latencies.iter().sum::<f64>() / answered as f64),
+            p95_ms: p95(&latencies),
+            max_ms: latencies.last().copied(),
+        }
+    }
+}
+
+// Waits for the echo of one probe, ignoring everything else (including late
+// echoes of earlier probes)
+async fn wait_for_echo(receiver: &mut broadcast::Receiver<SerialLine>, probe: &str, deadline: Instant) -> bool {
+    loop {
+        match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            Ok(Ok(line)) if line.line.strip_prefix("Unknown command: ") == Some(probe) => return true,
+            Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => return false,
+        }
+    }
+}
+
+/// Measures the round trip to the firmware with probes sent one after the
+/// other. The times include up to 10 ms of polling by the serial reader.
+pub async fn serial_benchmark(Json(payload): Json<BenchmarkRequest>) -> Result<Json<BenchmarkReport>, (StatusCode, Json<CommandError>)> {
+    if !(1..=MAX_PROBES).contains(&payload.count) || !(1..=MAX_PROBE_TIMEOUT_MS).contains(&payload.timeout_ms) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(CommandError::new(
+                "invalid_benchmark",
+                format!("count must be 1 to {} and timeout_ms 1 to {}", MAX_PROBES, MAX_PROBE_TIMEOUT_MS),
+            )),
+        ));
+    }
+
+    let mut receiver = SERIAL_LINES.subscribe();
+    let timeout = Duration::from_millis(payload.timeout_ms);
+    let mut latencies = Vec::new();
+
+    for i in 0..payload.count {
+        let probe = format!("{} {}", PROBE_COMMAND, i);
+        let start = Instant::now();
+        write_bytes(format!("{}\n", probe).as_bytes(), false)
+            .await
+            .map_err(|e| (e.status(), Json(e.into())))?;
+
+        if wait_for_echo(&mut receiver, &probe, start + timeout).await {
+            latencies.push(start.elapsed().as_micros() as f64 / 1000.0);
+        }
+    }
+
+    Ok(Json(BenchmarkReport::new(payload.count, latencies)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(n: u32) -> Vec<f64> {
+        (1..=n).map(f64::from).collect()
+    }
+
+    // Same cases as for `bench` in `test_arduino_serial`
+    #[test]
+    fn p95_is_the_nearest_rank() {
+        assert_eq!(p95(&[]), None);
+        assert_eq!(p95(&[4.2]), Some(4.2));
+        assert_eq!(p95(&sorted(20)), Some(19.0));
+        assert_eq!(p95(&sorted(21)), Some(20.0));
+    }
+
+    #[test]
+    fn reports_the_answered_probes() {
+        let report = BenchmarkReport::new(5, vec![3.0, 1.0, 2.0]);
+        assert_eq!((report.sent, report.lost), (5, 2));
+        assert_eq!((report.min_ms, report.mean_ms, report.p95_ms, report.max_ms), (Some(1.0), Some(2.0), Some(3.0), Some(3.0)));
+
+        let report = BenchmarkReport::new(2, Vec::new());
+        assert_eq!(report.lost, 2);
+        assert_eq!((report.min_ms, report.mean_ms, report.p95_ms, report.max_ms), (None, None, None, None));
+    }
+}
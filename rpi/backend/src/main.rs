@@ -3,27 +3,107 @@ use axum::{
     routing::{get, post},
     Router,
 };
-use tokio::sync::Mutex;
+use std::time::Duration;
 use tower_http::cors::{CorsLayer, Any};
-use realsense_rust::{
-    context::Context, frame::{ColorFrame, DepthFrame, PixelKind}, kind::{Rs2Format, Rs2StreamKind}, pipeline::InactivePipeline, processing_blocks::align::Align
-};
-use image::{ImageBuffer, Rgb, RgbImage};
-use std::{sync::Arc, time::Duration};
 
+mod battery;
+use battery::{battery_status, configure_battery, battery_events};
+mod calibration;
+use calibration::{calibration_status, calibrate_imu, reset_calibration};
+mod camera;
+mod command;
+use command::send_command;
+mod config;
+mod connection;
+use connection::{connection_status, connection_events};
+mod events;
+mod firmware;
+use firmware::firmware_info;
+mod framing;
+mod hardware;
+use hardware::{open_camera, open_imu};
+mod imu;
+use imu::{imu_status, latest_sample, imu_samples, imu_settings, configure_imu};
+mod latency;
+use latency::serial_benchmark;
+mod mecanum;
+use mecanum::send_velocity;
+#[cfg(feature = "hardware")]
 mod mpu6050;
-use mpu6050::MPU6050;
+mod orientation;
+use orientation::{orientation_status, reset_heading, orientation_events};
+mod odometry;
+use odometry::{odometry_status, odometry_path, reset_odometry};
+#[cfg(feature = "hardware")]
+mod realsense;
+mod ramp;
+use ramp::{ramp_status, configure_ramp};
 mod recording;
 use recording::{IS_RECORDING, COLOR_FRAMES, DEPTH_FRAMES, start_recording, stop_recording, download_recordings};
+mod route;
+use route::{recorder_status, start_route_recording, stop_route_recording, list_routes, get_route, replay_route};
+mod script;
+use script::{list_scripts, upload_script, start_script, pause_script, resume_script, abort_script, script_progress};
 mod serial;
-use serial::{list_serial_devices, connect, disconnect, send, read_mpu6050};
+use serial::{list_serial_devices, connect, disconnect, send};
+mod serial_reader;
+use serial_reader::{serial_log, serial_events};
+mod simulation;
+#[cfg(test)]
+mod test_support;
+mod virtual_arduino;
+use virtual_arduino::{spawn_pty, virtual_arduino_state};
+mod watchdog;
+use watchdog::{watchdog_status, configure_watchdog, heartbeat};
 mod websocket;
-use websocket::{LAST_FRAME, websocket_handler};
+use websocket::{LAST_FRAME, websocket_handler, control_websocket_handler, imu_websocket_handler};
+
+// Pause after a failed capture, in case the camera fails right away
+const CAMERA_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 #[tokio::main]
 async fn main() {
-    // Initialize MPU6050
-    let mpu = Arc::new(Mutex::new(MPU6050::new().expect("Failed to initialize MPU6050")));
+    if config::CONFIG.simulation {
+        println!("🧪 Running with simulated camera, IMU and Arduino");
+    }
+
+    if config::CONFIG.virtual_arduino {
+        match spawn_pty() {
+            Ok(port_path) => println!("🤖 Virtual Arduino listening on {}", port_path),
+            Err(e) => eprintln!("Failed to start virtual Arduino: {}", e),
+        }
+    }
+
+    // Stop the robot when the operator goes silent
+    watchdog::spawn();
+
+    // Collect everything the Arduino prints
+    serial_reader::spawn();
+
+    // Bring the wheels to new speeds gradually
+    ramp::spawn();
+
+    // Follow the robot's position from the wheel speeds
+    odometry::spawn();
+
+    // Stop the robot when the battery runs low, if enabled
+    battery::spawn();
+
+    // Reopen the serial port when the Arduino is unplugged or resets
+    connection::spawn();
+
+    // Initialize MPU6050 and sample it in the background. Without it the other
+    // routes keep working, and `/imu/settings` tries to open it again.
+    let imu = match open_imu(&config::CONFIG.imu_settings) {
+        Ok(imu) => Some(imu),
+        Err(e) => {
+            eprintln!("Failed to initialize MPU6050: {}", e);
+            None
+        }
+    };
+    imu::spawn(imu);
+    // Estimate roll, pitch and yaw from the samples
+    orientation::spawn();
 
     
     let handle = tokio::runtime::Handle::current();
@@ -31,40 +111,35 @@ async fn main() {
     // Task to capture frames from the camera
     //tokio::spawn(async move {
     std::thread::spawn(move || {
-        let mut config = realsense_rust::config::Config::new();
-        let _ = config
-            .enable_stream(Rs2StreamKind::Color, None, 640, 360, Rs2Format::Bgr8, 15).unwrap()
-            .enable_stream(Rs2StreamKind::Depth, None, 640, 360, Rs2Format::Z16, 15).unwrap();
-
-        let context = Context::new().unwrap();
-        let pipeline = InactivePipeline::try_from(&context).unwrap();
-        let mut pipeline = pipeline.start(Some(config)).unwrap();
-
-        let mut align = Align::new(Rs2StreamKind::Color, 10).expect("Failed to create align block");
+        let mut camera = match open_camera() {
+            Ok(camera) => camera,
+            Err(e) => {
+                eprintln!("Failed to initialize camera, streaming and recording are unavailable: {}", e);
+                return;
+            }
+        };
+        let mut failing = false;
 
         loop {
-            let timeout = Duration::from_millis(5000);
-            let frames = pipeline.wait(Some(timeout)).unwrap();
-
-            align.queue(frames).unwrap();
-            let aligned_frames = match align.wait(Duration::from_millis(100)) {
-                Ok(f) => f,
-                Err(_) => continue,
+            let (color_frame_data, depth_frame_data) = match camera.next_frames() {
+                Ok(frames) => {
+                    if failing {
+                        println!("Camera: capturing again");
+                        failing = false;
+                    }
+                    frames
+                }
+                Err(e) => {
+                    // Skip the frame, only reporting the first error of a series
+                    if !failing {
+                        eprintln!("Camera: failed to capture frames: {}", e);
+                        failing = true;
+                    }
+                    std::thread::sleep(CAMERA_RETRY_DELAY);
+                    continue;
+                }
             };
 
-            let mut color_frames = aligned_frames.frames_of_type::<ColorFrame>();
-            let mut depth_frames = aligned_frames.frames_of_type::<DepthFrame>();
-
-            if color_frames.is_empty() || depth_frames.is_empty() {
-                continue;
-            }
-
-            let color_frame = color_frames.pop().unwrap();
-            let depth_frame = depth_frames.pop().unwrap();
-
-            let color_frame_data = encode_color_frame(&color_frame);
-            let depth_frame_data = encode_depth_frame(&depth_frame);
-
             // Block on handle
             handle.block_on(async {
                 // Update last frame
@@ -96,13 +171,52 @@ async fn main() {
         .route("/list", get(list_serial_devices))
         .route("/connect", post(connect))
         .route("/disconnect", post(disconnect))
+        .route("/connection", get(connection_status))
+        .route("/connection_events", get(connection_events)) // Connection state changes as server-sent events
+        .route("/firmware", get(firmware_info))
         .route("/send", post(send))
-        .route("/read_imu", get(read_mpu6050))
+        .route("/serial_log", get(serial_log))
+        .route("/serial_events", get(serial_events)) // Arduino output as server-sent events
+        .route("/serial_benchmark", post(serial_benchmark)) // Round-trip latency to the firmware
+        .route("/command", post(send_command))
+        .route("/velocity", post(send_velocity))
+        .route("/ramp", get(ramp_status).post(configure_ramp))
+        .route("/odometry", get(odometry_status))
+        .route("/odometry/path", get(odometry_path))
+        .route("/odometry/reset", post(reset_odometry))
+        .route("/scripts", get(list_scripts).post(upload_script))
+        .route("/script", get(script_progress))
+        .route("/script/start", post(start_script))
+        .route("/script/pause", post(pause_script))
+        .route("/script/resume", post(resume_script))
+        .route("/script/abort", post(abort_script))
+        .route("/routes", get(list_routes))
+        .route("/routes/:name", get(get_route))
+        .route("/route_recording", get(recorder_status))
+        .route("/route_recording/start", post(start_route_recording))
+        .route("/route_recording/stop", post(stop_route_recording))
+        .route("/route_replay", post(replay_route)) // Progress and control via /script
+        .route("/heartbeat", post(heartbeat))
+        .route("/watchdog", get(watchdog_status).post(configure_watchdog))
+        .route("/battery", get(battery_status).post(configure_battery))
+        .route("/battery_events", get(battery_events)) // Low battery warnings as server-sent events
+        .route("/read_imu", get(latest_sample))
+        .route("/imu", get(imu_status))
+        .route("/imu/latest", get(latest_sample))
+        .route("/imu/samples", get(imu_samples)) // ?duration_ms=1000 or ?since_us=<t_us>
+        .route("/imu/settings", get(imu_settings).post(configure_imu))
+        .route("/imu/calibration", get(calibration_status).post(calibrate_imu))
+        .route("/imu/calibration/reset", post(reset_calibration))
+        .route("/orientation", get(orientation_status))
+        .route("/orientation/reset_heading", post(reset_heading))
+        .route("/orientation_events", get(orientation_events)) // Roll, pitch and yaw as server-sent events
+        .route("/virtual_arduino", get(virtual_arduino_state))
         .route("/camera_ws", get(websocket_handler)) // Camera websocket
+        .route("/control_ws", get(control_websocket_handler)) // Driving commands and Arduino replies
+        .route("/imu_ws", get(imu_websocket_handler)) // IMU samples as they are read
         .route("/start_recording", post(start_recording))
         .route("/stop_recording", post(stop_recording))
         .route("/download_recordings", get(download_recordings))
-        .with_state(mpu)
         .layer(cors); // CORS middleware
 
     let addr = "0.0.0.0:5000";
@@ -110,93 +224,3 @@ async fn main() {
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
-
-fn encode_color_frame(color_frame: &ColorFrame) -> Vec<u8> {
-    let width = color_frame.width() as u32;
-    let height = color_frame.height() as u32;
-
-    let mut img_buf = ImageBuffer::new(width, height);
-
-    for (x, y, pixel) in img_buf.enumerate_pixels_mut() {
-        if let Some(PixelKind::Bgr8 { b, g, r }) = color_frame.get(x as usize, y as usize) {
-            *pixel = Rgb([*r, *g, *b]);
-        }
-    }
-
-    let mut encoded_img = Vec::new();
-    img_buf.write_to(&mut std::io::Cursor::new(&mut encoded_img), image::ImageOutputFormat::Png).unwrap();
-    encoded_img
-}
-
-fn depth_to_color(normalized: f32) -> [u8; 3] {
-    // Invert the normalized value so nearer points get higher values
-    let inverted = 1.0 - normalized;
-    let inverted = inverted.clamp(0.0, 1.0);
-
-    // Jet colormap: blue -> cyan -> green -> yellow -> red
-    let mut r = 0.0;
-    let mut g = 0.0;
-    let mut b = 0.0;
-
-    if inverted < 0.25 {
-        b = 0.5 + 2.0 * inverted;
-    } else if inverted < 0.5 {
-        b = 1.0;
-        g = -1.0 + 4.0 * inverted;
-    } else if inverted < 0.75 {
-        b = -3.0 + 4.0 * inverted;
-        g = 1.0;
-        r = -0.5 + 2.0 * inverted;
-    } else {
-        g = 1.0 - 4.0 * (inverted - 0.75);
-        r = 1.0;
-    }
-
-    [
-        (r * 255.0) as u8,
-        (g * 255.0) as u8,
-        (b * 255.0) as u8,
-    ]
-}
-
-fn encode_depth_frame(depth_frame: &DepthFrame) -> Vec<u8> {
-    let width = depth_frame.width();
-    let height = depth_frame.height();
-    let mut img_buf = RgbImage::new(width as u32, height as u32);
-
-    // Get the multiplier to convert raw units to meters (millimeters to meters: 0.001)
-    let units = depth_frame.depth_units().unwrap_or(0.001);
-
-    let raw_data: &[u16] = unsafe {
-        let ptr = depth_frame.get_data() as *const _ as *const u16; 
-        std::slice::from_raw_parts(ptr, (width * height) as usize)
-    };
-
-    // Visualization range in meters (adjust based on your environment)
-    let min_m = 0.2; 
-    let max_m = 5.0; 
-
-    for (i, &raw_val) in raw_data.iter().enumerate() {
-        let x = (i % width) as u32;
-        let y = (i / width) as u32;
-
-        let dist_m = raw_val as f32 * units;
-
-        let color = if raw_val == 0 {
-            [0, 0, 0] // Black for no-data/out-of-range
-        } else {
-            // Normalize to 0.0 - 1.0 for the colormap
-            let normalized = ((dist_m - min_m) / (max_m - min_m)).clamp(0.0, 1.0);
-            depth_to_color(normalized)
-        };
-
-        img_buf.put_pixel(x, y, Rgb(color));
-    }
-
-    let mut encoded_img = Vec::new();
-    img_buf.write_to(
-        &mut std::io::Cursor::new(&mut encoded_img),
-        image::ImageOutputFormat::Png,
-    ).unwrap();
-    encoded_img
-}
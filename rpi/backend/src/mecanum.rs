@@ -0,0 +1,123 @@
+use axum::http::StatusCode;
+use axum::response::Json;
+use serde::{Serialize, Deserialize};
+
+use crate::command::{CommandError, MotionCommand, MAX_SPEED};
+use crate::serial::{Delivery, write_line};
+
+/// Speeds passed to `setAllMotors(lf, lb, rf, rb)` by the firmware, in steps per second
+#[derive(Clone, Copy, Default, Serialize, Deserialize, PartialEq, Debug)]
+pub struct WheelSpeeds {
+    pub lf: i32,
+    pub lb: i32,
+    pub rf: i32,
+    pub rb: i32,
+}
+
+/// Body velocity as fractions of the maximum wheel speed, each in [-1, 1]
+#[derive(Clone, Copy, Deserialize, Debug)]
+pub struct BodyVelocity {
+    /// Forward
+    pub vx: f32,
+    /// Sideways, positive to the left
+    pub vy: f32,
+    /// Yaw rate, positive counterclockwise (turning left)
+    pub omega: f32,
+}
+
+/// Computes the wheel speeds for a body velocity, with the same sign
+/// conventions as the discrete motions of the firmware (`forward` is all
+/// positive, `left` is `(-s, s, s, -s)`, `rotate_left` is `(-s, -s, s, s)`).
+/// Wheel speeds are scaled down together when one would exceed `max_speed`.
+pub fn mix(velocity: BodyVelocity, max_speed: i32) -> WheelSpeeds {
+    let BodyVelocity { vx, vy, omega } = velocity;
+    let wheels = [
+        vx - vy - omega, // lf
+        vx + vy - omega, // lb
+        vx + vy + omega, // rf
+        vx - vy + omega, // rb
+    ];
+
+    let largest = wheels.iter().fold(1.0f32, |acc, w| acc.max(w.abs()));
+    let [lf, lb, rf, rb] = wheels.map(|w| (w / largest * max_speed as f32).round() as i32);
+    WheelSpeeds { lf, lb, rf, rb }
+}
+
+#[derive(Deserialize)]
+pub struct VelocityRequest {
+    #[serde(flatten)]
+    velocity: BodyVelocity,
+    /// Wheel speed for a component of 1.0, in steps per second
+    max_speed: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct VelocityResponse {
+    wheels: WheelSpeeds,
+    sent: String,
+    delivery: Delivery,
+}
+
+impl VelocityRequest {
+    /// Validates the request and computes the wheel speeds for it
+    pub fn wheel_speeds(&self) -> Result<WheelSpeeds, CommandError> {
+        let BodyVelocity { vx, vy, omega } = self.velocity;
+        if [vx, vy, omega].iter().any(|v| !v.is_finite() || v.abs() > 1.0) {
+            return Err(CommandError::new(
+                "velocity_out_of_range",
+                "vx, vy and omega must be between -1 and 1".to_string(),
+            ));
+        }
+
+        let max_speed = self.max_speed.unwrap_or(MAX_SPEED);
+        if !(0..=MAX_SPEED).contains(&max_speed) {
+            return Err(CommandError::new(
+                "speed_out_of_range",
+                format!("max_speed must be between 0 and {}, got {}", MAX_SPEED, max_speed),
+            ));
+        }
+
+        Ok(mix(self.velocity, max_speed))
+    }
+}
+
+pub async fn send_velocity(Json(payload): Json<VelocityRequest>) -> Result<Json<VelocityResponse>, (StatusCode, Json<CommandError>)> {
+    let wheels = payload.wheel_speeds().map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, Json(e)))?;
+
+    let line = MotionCommand::Wheels(wheels).to_string();
+    let delivery = write_line(&line).await.map_err(|e| (e.status(), Json(e.into())))?;
+
+    Ok(Json(VelocityResponse { wheels, sent: line, delivery }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn velocity(vx: f32, vy: f32, omega: f32) -> BodyVelocity {
+        BodyVelocity { vx, vy, omega }
+    }
+
+    #[test]
+    fn pure_motions_match_the_firmware() {
+        assert_eq!(mix(velocity(1.0, 0.0, 0.0), 1000), WheelSpeeds { lf: 1000, lb: 1000, rf: 1000, rb: 1000 });
+        // `left` and `rotate_left`
+        assert_eq!(mix(velocity(0.0, 1.0, 0.0), 1000), WheelSpeeds { lf: -1000, lb: 1000, rf: 1000, rb: -1000 });
+        assert_eq!(mix(velocity(0.0, 0.0, 1.0), 1000), WheelSpeeds { lf: -1000, lb: -1000, rf: 1000, rb: 1000 });
+        assert_eq!(mix(velocity(0.0, 0.0, 0.0), 1000), WheelSpeeds::default());
+    }
+
+    #[test]
+    fn combined_motions_are_scaled_down_together() {
+        // Diagonal forward left would need twice the maximum on two wheels
+        assert_eq!(mix(velocity(1.0, 1.0, 0.0), 1000), WheelSpeeds { lf: 0, lb: 1000, rf: 1000, rb: 0 });
+        let wheels = mix(velocity(1.0, 0.5, 0.5), 2000);
+        assert!([wheels.lf, wheels.lb, wheels.rf, wheels.rb].iter().all(|w| w.abs() <= 2000));
+        assert_eq!(wheels.rf, 2000);
+    }
+
+    #[test]
+    fn slow_motions_are_not_scaled_up() {
+        assert_eq!(mix(velocity(0.25, 0.0, 0.25), 1000), WheelSpeeds { lf: 0, lb: 0, rf: 500, rb: 500 });
+    }
+}
@@ -1,25 +1,110 @@
 use mpu6050::*;
+use mpu6050::device::{AccelRange, GyroRange};
 use linux_embedded_hal::{I2cdev, Delay};
 
+use crate::hardware::{DLPF_BANDWIDTHS_HZ, Imu, ImuSettings};
+
+// Sample rate divider register, not named by the mpu6050 crate
+const SMPLRT_DIV: u8 = 0x19;
+
 pub struct MPU6050 {
     mpu: Mpu6050<I2cdev>,
 }
 
+// The settings are validated before, but a value missed here must not silently
+// open the sensor with another range
+fn accel_range(g: u8) -> Result<AccelRange, String> {
+    match g {
+        2 => Ok(AccelRange::G2),
+        4 => Ok(AccelRange::G4),
+        8 => Ok(AccelRange::G8),
+        16 => Ok(AccelRange::G16),
+        _ => Err(format!("unsupported accelerometer range {} g", g)),
+    }
+}
+
+fn gyro_range(dps: u16) -> Result<GyroRange, String> {
+    match dps {
+        250 => Ok(GyroRange::D250),
+        500 => Ok(GyroRange::D500),
+        1000 => Ok(GyroRange::D1000),
+        2000 => Ok(GyroRange::D2000),
+        _ => Err(format!("unsupported gyroscope range {} °/s", dps)),
+    }
+}
+
+// DLPF_CFG register value
+fn dlpf_config(hz: u16) -> Result<u8, String> {
+    match DLPF_BANDWIDTHS_HZ.iter().position(|&bandwidth| bandwidth == hz) {
+        Some(config) => Ok(config as u8),
+        None => Err(format!("unsupported low-pass filter bandwidth {} Hz", hz)),
+    }
+}
+
+fn mpu_error<E: std::fmt::Debug>(e: Mpu6050Error<E>) -> Box<dyn std::error::Error> {
+    format!("MPU6050: {:?}", e).into()
+}
+
 impl MPU6050 {
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let i2c = I2cdev::new("/dev/i2c-1")?; // Default I2C bus on Raspberry Pi
-        let mut mpu = Mpu6050::new(i2c);
+    pub fn new(settings: &ImuSettings) -> Result<Self, Box<dyn std::error::Error>> {
+        let accel_range = accel_range(settings.accel_range_g)?;
+        let gyro_range = gyro_range(settings.gyro_range_dps)?;
+        let dlpf_config = dlpf_config(settings.dlpf_hz)?;
+
+        let i2c = I2cdev::new(&settings.i2c_bus)?;
+        let mut mpu = Mpu6050::new_with_addr(i2c, settings.address.0);
+        // Wakes the sensor, then fails on clones reporting another WHO_AM_I, so
+        // its errors are ignored. When it succeeds it also resets the ranges.
+        // The writes below fail if nothing answers at the address.
         let _ = mpu.init(&mut Delay);
+        mpu.set_accel_range(accel_range).map_err(mpu_error)?;
+        mpu.set_gyro_range(gyro_range).map_err(mpu_error)?;
+        mpu.write_bits(device::CONFIG::ADDR, device::CONFIG::DLPF_CFG.bit, device::CONFIG::DLPF_CFG.length, dlpf_config)
+            .map_err(mpu_error)?;
+        mpu.write_byte(SMPLRT_DIV, settings.sample_rate_divider).map_err(mpu_error)?;
         Ok(Self { mpu })
     }
+}
 
-    pub fn read_accel(&mut self) -> Result<(f32, f32, f32), Box<dyn std::error::Error>> {
-        let accel = self.mpu.get_acc().unwrap();
+impl Imu for MPU6050 {
+    fn read_accel(&mut self) -> Result<(f32, f32, f32), Box<dyn std::error::Error>> {
+        let accel = self.mpu.get_acc().map_err(mpu_error)?;
         Ok((accel.x, accel.y, accel.z))
     }
 
-    pub fn read_gyro(&mut self) -> Result<(f32, f32, f32), Box<dyn std::error::Error>> {
-        let gyro = self.mpu.get_gyro().unwrap();
-        Ok((gyro.x, gyro.y, gyro.z))
+    fn read_gyro(&mut self) -> Result<(f32, f32, f32), Box<dyn std::error::Error>> {
+        // The crate gives radians per second
+        let gyro = self.mpu.get_gyro().map_err(mpu_error)?;
+        Ok((gyro.x.to_degrees(), gyro.y.to_degrees(), gyro.z.to_degrees()))
+    }
+
+    fn configure(&mut self, settings: &ImuSettings) -> Result<(), Box<dyn std::error::Error>> {
+        *self = Self::new(settings)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::{ACCEL_RANGES_G, GYRO_RANGES_DPS};
+
+    // The register values are the positions in the tables `validate` checks against
+    #[test]
+    fn supported_settings_map_to_their_registers() {
+        for (afs_sel, g) in ACCEL_RANGES_G.into_iter().enumerate() {
+            assert_eq!(accel_range(g).map(|range| range as usize), Ok(afs_sel));
+        }
+        for (fs_sel, dps) in GYRO_RANGES_DPS.into_iter().enumerate() {
+            assert_eq!(gyro_range(dps).map(|range| range as usize), Ok(fs_sel));
+        }
+        assert_eq!(DLPF_BANDWIDTHS_HZ.map(dlpf_config), [0, 1, 2, 3, 4, 5, 6].map(Ok));
+    }
+
+    #[test]
+    fn rejects_unsupported_settings() {
+        assert!(accel_range(3).is_err());
+        assert!(gyro_range(125).is_err());
+        assert!(dlpf_config(100).is_err());
     }
 }
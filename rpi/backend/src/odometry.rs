@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::time::Duration;
+use axum::response::Json;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::config::CONFIG;
+use crate::events::now_ms;
+use crate::mecanum::WheelSpeeds;
+
+// How often the pose is added to the path while moving
+const PATH_INTERVAL: Duration = Duration::from_millis(100);
+
+// About 10 minutes of driving
+const MAX_PATH_POINTS: usize = 6000;
+
+static ODOMETRY: Lazy<Mutex<Odometry>> = Lazy::new(|| {
+    let pose = Pose { timestamp_ms: now_ms(), ..Default::default() };
+    Mutex::new(Odometry {
+        pose,
+        velocity: Velocity::default(),
+        updated: Instant::now(),
+        distance_m: 0.0,
+        path: VecDeque::from([pose]),
+    })
+});
+
+/// Position in meters from where odometry was last reset, `x` pointing
+/// forward and `y` to the left at that time, and heading in radians,
+/// counterclockwise in (-π, π]
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pose {
+    pub timestamp_ms: u64,
+    pub x: f32,
+    pub y: f32,
+    pub theta: f32,
+}
+
+/// Body velocity in meters and radians per second, in the robot's frame
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Velocity {
+    pub vx: f32,
+    pub vy: f32,
+    pub omega: f32,
+}
+
+impl Velocity {
+    /// Inverse of `mecanum::mix`, with the wheel speeds in steps per second
+    fn of(wheels: WheelSpeeds) -> Self {
+        let [lf, lb, rf, rb] = [wheels.lf, wheels.lb, wheels.rf, wheels.rb].map(|w| w as f32 / CONFIG.steps_per_meter);
+        // Distance from the center to the wheels along both axes
+        let lever = (CONFIG.wheelbase_m + CONFIG.track_width_m) / 2.0;
+        Self {
+            vx: (lf + lb + rf + rb) / 4.0,
+            vy: (-lf + lb + rf - rb) / 4.0,
+            omega: (-lf - lb + rf + rb) / (4.0 * lever),
+        }
+    }
+}
+
+/// Dead reckoning from the wheel speeds sent to the Arduino. It doesn't see
+/// wheel slip or stalls, so it drifts and is only a first estimate.
+struct Odometry {
+    pose: Pose,
+    velocity: Velocity,
+    /// When `pose` was last brought up to date
+    updated: Instant,
+    distance_m: f32,
+    path: VecDeque<Pose>,
+}
+
+impl Odometry {
+    // Moves the pose along the current velocity up to now. The velocity is
+    // constant in the robot's frame meanwhile, so it follows an arc.
+    fn advance(&mut self) {
+        let dt = self.updated.elapsed().as_secs_f32();
+        self.updated = Instant::now();
+
+        let Velocity { vx, vy, omega } = self.velocity;
+        let theta = self.pose.theta;
+        let (dx, dy) = if omega.abs() < 1e-6 {
+            let (sin, cos) = theta.sin_cos();
+            ((vx * cos - vy * sin) * dt, (vx * sin + vy * cos) * dt)
+        } else {
+            let (sin0, cos0) = theta.sin_cos();
+            let (sin1, cos1) = (theta + omega * dt).sin_cos();
+            (
+                (vx * (sin1 - sin0) + vy * (cos1 - cos0)) / omega,
+                (vy * (sin1 - sin0) - vx * (cos1 - cos0)) / omega,
+            )
+        };
+
+        self.pose = Pose {
+            timestamp_ms: now_ms(),
+            x: self.pose.x + dx,
+            y: self.pose.y + dy,
+            theta: wrap_angle(theta + omega * dt),
+        };
+        self.distance_m += dx.hypot(dy);
+    }
+
+    fn add_to_path(&mut self) {
+        // Nothing new while standing still
+        if self.path.back().is_some_and(|p| p.x == self.pose.x && p.y == self.pose.y && p.theta == self.pose.theta) {
+            return;
+        }
+        if self.path.len() >= MAX_PATH_POINTS {
+            self.path.pop_front();
+        }
+        self.path.push_back(self.pose);
+    }
+}
+
+fn wrap_angle(angle: f32) -> f32 {
+    let wrapped = (angle + PI).rem_euclid(2.0 * PI) - PI;
+    if wrapped == -PI { PI } else { wrapped }
+}
+
+/// Records new wheel speeds sent to the Arduino. Called with the ramp locked,
+/// so it must not lock the ramp or write to the serial port.
+pub async fn update(wheels: WheelSpeeds) {
+    let mut odometry = ODOMETRY.lock().await;
+    odometry.advance();
+    odometry.velocity = Velocity::of(wheels);
+    odometry.add_to_path();
+}
+
+/// Starts the task adding the pose to the path while moving
+pub fn spawn() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(PATH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut odometry = ODOMETRY.lock().await;
+            if odometry.velocity != Velocity::default() {
+                odometry.advance();
+                odometry.add_to_path();
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+pub struct OdometryStatus {
+    pose: Pose,
+    velocity: Velocity,
+    /// Distance travelled since the last reset
+    distance_m: f32,
+}
+
+pub async fn odometry_status() -> Json<OdometryStatus> {
+    let mut odometry = ODOMETRY.lock().await;
+    odometry.advance();
+    Json(OdometryStatus {
+        pose: odometry.pose,
+        velocity: odometry.velocity,
+        distance_m: odometry.distance_m,
+    })
+}
+
+pub async fn odometry_path() -> Json<Vec<Pose>> {
+    let mut odometry = ODOMETRY.lock().await;
+    odometry.advance();
+    odometry.add_to_path();
+    Json(odometry.path.iter().copied().collect())
+}
+
+/// Makes the current position the origin, facing along `x`
+pub async fn reset_odometry() -> Json<OdometryStatus> {
+    {
+        let mut odometry = ODOMETRY.lock().await;
+        odometry.advance();
+        odometry.pose = Pose { timestamp_ms: now_ms(), ..Default::default() };
+        odometry.distance_m = 0.0;
+        odometry.path.clear();
+        odometry.add_to_path();
+    }
+    odometry_status().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mecanum::{BodyVelocity, mix};
+    use crate::test_support::assert_close;
+
+    #[test]
+    fn velocity_inverts_the_mecanum_mix() {
+        let max_speed = 1000;
+        let steps = CONFIG.steps_per_meter;
+        let lever = (CONFIG.wheelbase_m + CONFIG.track_width_m) / 2.0;
+
+        for (vx, vy, omega) in [(1.0, 0.0, 0.0), (0.0, -1.0, 0.0), (0.0, 0.0, 1.0), (0.3, -0.2, 0.4)] {
+            let velocity = Velocity::of(mix(BodyVelocity { vx, vy, omega }, max_speed));
+            let scale = max_speed as f32 / steps;
+            assert_close(velocity.vx, vx * scale, 1e-3);
+            assert_close(velocity.vy, vy * scale, 1e-3);
+            assert_close(velocity.omega, omega * scale / lever, 1e-3);
+        }
+    }
+
+    #[test]
+    fn standing_still_has_no_velocity() {
+        assert_eq!(Velocity::of(WheelSpeeds::default()), Velocity::default());
+    }
+
+    #[test]
+    fn angles_wrap_to_half_open_range() {
+        assert_close(wrap_angle(0.5), 0.5, 1e-5);
+        assert_close(wrap_angle(PI + 0.5), -PI + 0.5, 1e-5);
+        assert_close(wrap_angle(-PI - 0.5), PI - 0.5, 1e-5);
+        assert_eq!(wrap_angle(-PI), PI);
+        assert_close(wrap_angle(4.0 * PI + 0.25), 0.25, 1e-5);
+    }
+}
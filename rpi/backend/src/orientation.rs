@@ -0,0 +1,208 @@
+use std::convert::Infallible;
+use axum::http::StatusCode;
+use axum::response::{Json, sse::{Event, Sse}};
+use futures_util::stream::Stream;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::{Mutex, broadcast};
+
+use crate::config::CONFIG;
+use crate::events::sse_from_broadcast;
+use crate::imu::{IMU_STREAM, ImuSample};
+
+// Gaps in the samples longer than this restart the filter from the accelerometer
+const MAX_GAP_US: u64 = 100_000;
+
+// The accelerometer only gives the tilt when nothing but gravity acts on it,
+// so it is ignored during bumps and hard acceleration
+const MIN_TRUSTED_ACCEL_G: f32 = 0.85;
+const MAX_TRUSTED_ACCEL_G: f32 = 1.15;
+
+// Roll and yaw rates grow without bound pointing straight up or down, so the
+// conversion of the gyro rates uses a pitch at most this steep
+const MAX_RATE_PITCH_DEG: f32 = 89.0;
+
+// How often the orientation is streamed to `/orientation_events`
+const PUBLISH_INTERVAL_US: u64 = 20_000;
+
+static FILTER: Lazy<Mutex<Filter>> = Lazy::new(|| Mutex::new(Filter::default()));
+
+static ORIENTATION_EVENTS: Lazy<broadcast::Sender<Orientation>> = Lazy::new(|| broadcast::channel(64).0);
+
+/// Attitude in degrees: `roll` about the forward `x` axis and `pitch` about
+/// the left `y` axis, both 0 lying flat, and `yaw` about `z`, counterclockwise
+/// in (-180, 180] from the heading at the last reset
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Orientation {
+    /// Of the sample the orientation was last updated with, see `ImuSample`
+    pub t_us: u64,
+    pub timestamp_ms: u64,
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+/// Complementary filter: integrates the gyro, and pulls roll and pitch
+/// towards the tilt given by gravity so they don't drift. Yaw has no such
+/// reference and drifts with the remaining gyro bias.
+#[derive(Default)]
+struct Filter {
+    orientation: Option<Orientation>,
+    last_published_us: u64,
+}
+
+fn wrap_degrees(angle: f32) -> f32 {
+    let wrapped = (angle + 180.0).rem_euclid(360.0) - 180.0;
+    if wrapped == -180.0 { 180.0 } else { wrapped }
+}
+
+// Roll and pitch of the gravity vector, in degrees
+fn tilt((ax, ay, az): (f32, f32, f32)) -> (f32, f32) {
+    (ay.atan2(az).to_degrees(), (-ax).atan2(ay.hypot(az)).to_degrees())
+}
+
+impl Filter {
+    fn update(&mut self, sample: ImuSample) -> Orientation {
+        let (ax, ay, az) = sample.accel;
+        let accel_g = (ax * ax + ay * ay + az * az).sqrt();
+        let trusted = (MIN_TRUSTED_ACCEL_G..=MAX_TRUSTED_ACCEL_G).contains(&accel_g);
+
+        let (roll, pitch, yaw) = match self.orientation {
+            Some(last) if sample.t_us > last.t_us && sample.t_us - last.t_us <= MAX_GAP_US => {
+                let dt = (sample.t_us - last.t_us) as f32 / 1e6;
+                let (gx, gy, gz) = sample.gyro;
+                let (sin_roll, cos_roll) = last.roll.to_radians().sin_cos();
+                let rate_pitch = last.pitch.clamp(-MAX_RATE_PITCH_DEG, MAX_RATE_PITCH_DEG).to_radians();
+                let (cos_pitch, tan_pitch) = (rate_pitch.cos(), rate_pitch.tan());
+
+                // Body rates to Euler angle rates
+                let mut roll = last.roll + (gx + (gy * sin_roll + gz * cos_roll) * tan_pitch) * dt;
+                let mut pitch = last.pitch + (gy * cos_roll - gz * sin_roll) * dt;
+                let yaw = last.yaw + (gy * sin_roll + gz * cos_roll) / cos_pitch * dt;
+
+                if trusted {
+                    let (accel_roll, accel_pitch) = tilt(sample.accel);
+                    let time_constant = CONFIG.orientation_time_constant_s.max(1e-3);
+                    let alpha = dt / (time_constant + dt);
+                    roll += alpha * wrap_degrees(accel_roll - roll);
+                    pitch += alpha * (accel_pitch - pitch);
+                }
+                (wrap_degrees(roll), pitch, wrap_degrees(yaw))
+            }
+            // First sample, or after a gap: start over from the accelerometer, keeping the heading
+            last => {
+                let (roll, pitch) = tilt(sample.accel);
+                (roll, pitch, last.map_or(0.0, |o| o.yaw))
+            }
+        };
+
+        let orientation = Orientation { t_us: sample.t_us, timestamp_ms: sample.timestamp_ms, roll, pitch, yaw };
+        self.orientation = Some(orientation);
+        orientation
+    }
+}
+
+/// Starts the task feeding the IMU samples to the filter
+pub fn spawn() {
+    tokio::spawn(async {
+        let mut samples = IMU_STREAM.subscribe();
+        loop {
+            let sample = match samples.recv().await {
+                Ok(sample) => sample,
+                // Fell behind, the next sample covers the gap
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            let mut filter = FILTER.lock().await;
+            let orientation = filter.update(sample);
+            if orientation.t_us.saturating_sub(filter.last_published_us) >= PUBLISH_INTERVAL_US {
+                filter.last_published_us = orientation.t_us;
+                let _ = ORIENTATION_EVENTS.send(orientation);
+            }
+        }
+    });
+}
+
+pub async fn orientation_status() -> Result<Json<Orientation>, (StatusCode, String)> {
+    match FILTER.lock().await.orientation {
+        Some(orientation) => Ok(Json(orientation)),
+        None => Err((StatusCode::SERVICE_UNAVAILABLE, "No IMU sample yet".to_string())),
+    }
+}
+
+/// Makes the current heading yaw 0
+pub async fn reset_heading() -> Result<Json<Orientation>, (StatusCode, String)> {
+    if let Some(orientation) = FILTER.lock().await.orientation.as_mut() {
+        orientation.yaw = 0.0;
+    }
+    orientation_status().await
+}
+
+pub async fn orientation_events() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    sse_from_broadcast(ORIENTATION_EVENTS.subscribe())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_close, imu_sample};
+
+    const FLAT: (f32, f32, f32) = (0.0, 0.0, 1.0);
+
+    #[test]
+    fn starts_from_the_accelerometer() {
+        let (sin, cos) = 10f32.to_radians().sin_cos();
+        let mut filter = Filter::default();
+        let orientation = filter.update(imu_sample(1, (0.0, sin, cos), (0.0, 0.0, 50.0)));
+        assert_close(orientation.roll, 10.0, 0.01);
+        assert_close(orientation.pitch, 0.0, 0.01);
+        assert_close(orientation.yaw, 0.0, 0.01);
+
+        let orientation = Filter::default().update(imu_sample(1, (-sin, 0.0, cos), (0.0, 0.0, 0.0)));
+        assert_close(orientation.roll, 0.0, 0.01);
+        assert_close(orientation.pitch, 10.0, 0.01);
+    }
+
+    #[test]
+    fn integrates_the_gyro_in_degrees_per_second() {
+        let mut filter = Filter::default();
+        let mut orientation = filter.update(imu_sample(0, FLAT, (0.0, 0.0, 10.0)));
+        for step in 1..=100 {
+            orientation = filter.update(imu_sample(step * 10_000, FLAT, (0.0, 0.0, 10.0)));
+        }
+        assert_close(orientation.yaw, 10.0, 0.01);
+        assert_close(orientation.roll, 0.0, 0.01);
+        assert_close(orientation.pitch, 0.0, 0.01);
+    }
+
+    #[test]
+    fn restarts_after_a_gap_keeping_the_heading() {
+        let mut filter = Filter::default();
+        filter.update(imu_sample(0, FLAT, (0.0, 0.0, 0.0)));
+        filter.update(imu_sample(10_000, FLAT, (0.0, 0.0, 1000.0)));
+        let (sin, cos) = 20f32.to_radians().sin_cos();
+        let orientation = filter.update(imu_sample(10_000 + MAX_GAP_US + 1, (0.0, sin, cos), (0.0, 0.0, 1000.0)));
+        assert_close(orientation.roll, 20.0, 0.01);
+        assert_close(orientation.yaw, 10.0, 0.01);
+    }
+
+    #[test]
+    fn stays_finite_pointing_straight_up() {
+        let mut filter = Filter::default();
+        let orientation = filter.update(imu_sample(0, (-1.0, 0.0, 0.0), (0.0, 0.0, 0.0)));
+        assert_close(orientation.pitch, 90.0, 0.01);
+        for step in 1..=10 {
+            let orientation = filter.update(imu_sample(step * 10_000, (-1.0, 0.0, 0.0), (5.0, 0.0, 5.0)));
+            assert!(orientation.roll.is_finite() && orientation.pitch.is_finite() && orientation.yaw.is_finite());
+        }
+    }
+
+    #[test]
+    fn wraps_degrees() {
+        assert_close(wrap_degrees(190.0), -170.0, 0.01);
+        assert_close(wrap_degrees(-190.0), 170.0, 0.01);
+        assert_close(wrap_degrees(720.0 + 45.0), 45.0, 0.01);
+        assert_eq!(wrap_degrees(-180.0), 180.0);
+    }
+}
@@ -0,0 +1,271 @@
+use std::time::Duration;
+use axum::http::StatusCode;
+use axum::response::Json;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tokio::sync::{Mutex, Notify, broadcast};
+use tokio::time::Instant;
+
+use crate::command::{MotionCommand, MAX_SPEED};
+use crate::config::CONFIG;
+use crate::connection::{CONNECTION, ConnectionState};
+use crate::firmware::FirmwareInfo;
+use crate::mecanum::WheelSpeeds;
+use crate::odometry;
+use crate::serial::{Delivery, SerialError, deliver};
+
+// How often intermediate wheel speeds are sent while ramping
+const RAMP_INTERVAL: Duration = Duration::from_millis(50);
+
+// Wheel speed used by the motion commands until `speed` is sent, as in the firmware
+const DEFAULT_SPEED: i32 = 1000;
+
+// The firmware jumps straight to a new speed, which stalls the steppers when the
+// change is large. Motion commands are therefore turned into a target, and the
+// wheels are brought to it with a series of `wheels` commands. The lock is held
+// while writing so the steps reach the Arduino in order, so the serial write
+// path must never lock it. Odometry is updated under it too, so it sees the
+// wheel speeds in the order they were sent, and never locks it in turn.
+static RAMP: Lazy<Mutex<Ramp>> = Lazy::new(|| {
+    Mutex::new(Ramp {
+        acceleration: CONFIG.acceleration,
+        speed: DEFAULT_SPEED,
+        current: WheelSpeeds::default(),
+        target: WheelSpeeds::default(),
+        last_step: Instant::now(),
+    })
+});
+
+// Wakes the ramp task when there is a new target
+static TARGET_CHANGED: Lazy<Notify> = Lazy::new(Notify::new);
+
+struct Ramp {
+    /// Steps per second squared, 0 to apply speed changes at once
+    acceleration: u32,
+    /// Speed setting of the firmware, used by the discrete motions
+    speed: i32,
+    /// Wheel speeds last sent to the Arduino
+    current: WheelSpeeds,
+    target: WheelSpeeds,
+    last_step: Instant,
+}
+
+impl Ramp {
+    // All wheels change proportionally so they reach the target together, with
+    // the one changing the most limited by the acceleration. Reversing goes
+    // through zero like any other change. Without acceleration limit the wheels
+    // jump to the target.
+    fn next_step(&self) -> WheelSpeeds {
+        let elapsed = self.last_step.elapsed().min(RAMP_INTERVAL);
+        let max_change = self.acceleration as f32 * elapsed.as_secs_f32();
+
+        let current = [self.current.lf, self.current.lb, self.current.rf, self.current.rb];
+        let target = [self.target.lf, self.target.lb, self.target.rf, self.target.rb];
+        let largest = current.iter().zip(&target).map(|(c, t)| (t - c).abs()).max().unwrap_or(0);
+        if self.acceleration == 0 || largest as f32 <= max_change {
+            return self.target;
+        }
+
+        let fraction = max_change / largest as f32;
+        let [lf, lb, rf, rb] = [0, 1, 2, 3].map(|i| current[i] + ((target[i] - current[i]) as f32 * fraction).round() as i32);
+        WheelSpeeds { lf, lb, rf, rb }
+    }
+
+    async fn set_current(&mut self, wheels: WheelSpeeds) {
+        self.current = wheels;
+        odometry::update(wheels).await;
+    }
+
+    // The Arduino starts over stopped and at the default speed once the port is
+    // reopened, and stops moving once unplugged
+    async fn forget(&mut self) {
+        self.speed = DEFAULT_SPEED;
+        self.target = WheelSpeeds::default();
+        self.set_current(WheelSpeeds::default()).await;
+    }
+
+    // Sends the next step towards the target
+    async fn step(&mut self, check_ready: bool) -> Result<Delivery, SerialError> {
+        let step = self.next_step();
+        let delivery = deliver(&MotionCommand::Wheels(step).to_string(), check_ready).await?;
+        self.set_current(step).await;
+        self.last_step = Instant::now();
+        if self.current != self.target {
+            TARGET_CHANGED.notify_one();
+        }
+        Ok(delivery)
+    }
+}
+
+/// Sends a motion command, ramping the wheels to its speeds if enabled and
+/// supported by the firmware, which needs the `wheels` command
+pub async fn drive(command: MotionCommand, line: &str, firmware: Option<&FirmwareInfo>, check_ready: bool) -> Result<Delivery, SerialError> {
+    let mut ramp = RAMP.lock().await;
+    let result = drive_locked(&mut ramp, command, line, firmware, check_ready).await;
+    if result.as_ref().is_err_and(SerialError::is_port_lost) {
+        ramp.forget().await;
+    }
+    result
+}
+
+async fn drive_locked(ramp: &mut Ramp, command: MotionCommand, line: &str, firmware: Option<&FirmwareInfo>, check_ready: bool) -> Result<Delivery, SerialError> {
+    let Some(target) = command.wheel_speeds(ramp.speed) else {
+        // `speed` only changes the setting, but the firmware keeps it too
+        let delivery = deliver(line, check_ready).await?;
+        if let MotionCommand::Speed { value } = command {
+            ramp.speed = value;
+        }
+        return Ok(delivery);
+    };
+
+    ramp.target = target;
+    // `stop` is how the watchdog, the battery monitor and aborted scripts halt
+    // the robot, so it is never ramped
+    let stopping = command == MotionCommand::Stop;
+    if stopping || ramp.acceleration == 0 || !firmware.is_some_and(|f| f.supports_command("wheels")) {
+        let delivery = deliver(line, check_ready).await?;
+        ramp.set_current(target).await;
+        return Ok(delivery);
+    }
+    ramp.step(check_ready).await
+}
+
+/// Speed setting of the firmware, which the discrete motions run at
+pub async fn speed() -> i32 {
+    RAMP.lock().await.speed
+}
+
+/// Forgets the wheel speeds and speed setting, e.g. when the Arduino was reset by opening the port
+pub async fn reset() {
+    RAMP.lock().await.forget().await;
+}
+
+/// Starts the tasks sending the intermediate wheel speeds, and resetting the
+/// ramp when the connection is lost
+pub fn spawn() {
+    tokio::spawn(async {
+        let mut events = CONNECTION.subscribe();
+        loop {
+            match events.recv().await {
+                // Sent once when the port is dropped, later attempts count up
+                Ok(event) if event.state == (ConnectionState::Reconnecting { attempt: 0 }) => reset().await,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    tokio::spawn(async {
+        loop {
+            TARGET_CHANGED.notified().await;
+
+            let mut ramp = RAMP.lock().await;
+            let wait = RAMP_INTERVAL.saturating_sub(ramp.last_step.elapsed());
+            if !wait.is_zero() {
+                drop(ramp);
+                tokio::time::sleep(wait).await;
+                ramp = RAMP.lock().await;
+            }
+            if ramp.current == ramp.target {
+                continue;
+            }
+
+            if let Err(e) = ramp.step(false).await {
+                eprintln!("Ramp: failed to send wheel speeds: {}", e);
+                if e.is_port_lost() {
+                    ramp.forget().await;
+                } else {
+                    // The wheels keep their last speed, nothing more can be done
+                    // until the next command
+                    ramp.target = ramp.current;
+                }
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+pub struct RampStatus {
+    acceleration: u32,
+    speed: i32,
+    current: WheelSpeeds,
+    target: WheelSpeeds,
+}
+
+pub async fn ramp_status() -> Json<RampStatus> {
+    let ramp = RAMP.lock().await;
+    Json(RampStatus {
+        acceleration: ramp.acceleration,
+        speed: ramp.speed,
+        current: ramp.current,
+        target: ramp.target,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RampSettings {
+    acceleration: u32,
+}
+
+pub async fn configure_ramp(Json(payload): Json<RampSettings>) -> Result<Json<RampStatus>, (StatusCode, String)> {
+    // Slower than this, stopping from full speed would take over a minute
+    if payload.acceleration != 0 && payload.acceleration < MAX_SPEED as u32 / 60 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("acceleration must be 0 (no ramping) or at least {}", MAX_SPEED / 60),
+        ));
+    }
+    RAMP.lock().await.acceleration = payload.acceleration;
+    Ok(ramp_status().await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Accelerates by 100 steps per second per interval
+    const ACCELERATION: u32 = 2000;
+
+    fn ramp(acceleration: u32, current: WheelSpeeds, target: WheelSpeeds) -> Ramp {
+        // A whole interval since the last step, the most one step covers
+        let last_step = Instant::now() - RAMP_INTERVAL;
+        Ramp { acceleration, speed: DEFAULT_SPEED, current, target, last_step }
+    }
+
+    fn wheels(lf: i32, lb: i32, rf: i32, rb: i32) -> WheelSpeeds {
+        WheelSpeeds { lf, lb, rf, rb }
+    }
+
+    #[test]
+    fn wheels_change_proportionally() {
+        let ramp = ramp(ACCELERATION, WheelSpeeds::default(), wheels(1000, 500, -1000, 0));
+        assert_eq!(ramp.next_step(), wheels(100, 50, -100, 0));
+
+        let ramp = Ramp { current: wheels(100, 50, -100, 0), ..ramp };
+        assert_eq!(ramp.next_step(), wheels(200, 100, -200, 0));
+    }
+
+    #[test]
+    fn reaches_a_close_target() {
+        let ramp = ramp(ACCELERATION, wheels(950, 950, 950, 950), wheels(1000, 1020, 1000, 1000));
+        assert_eq!(ramp.next_step(), wheels(1000, 1020, 1000, 1000));
+    }
+
+    #[test]
+    fn reverses_through_zero() {
+        let mut ramp = ramp(ACCELERATION, wheels(300, 300, -300, -300), wheels(-300, -300, 300, 300));
+        let mut steps = Vec::new();
+        while ramp.current != ramp.target {
+            ramp.current = ramp.next_step();
+            steps.push(ramp.current.lf);
+        }
+        assert_eq!(steps, [200, 100, 0, -100, -200, -300]);
+        assert_eq!(ramp.current.rf, 300);
+    }
+
+    #[test]
+    fn jumps_without_acceleration() {
+        let ramp = ramp(0, wheels(-3000, 0, 0, 3000), wheels(3000, 1000, -1000, -3000));
+        assert_eq!(ramp.next_step(), wheels(3000, 1000, -1000, -3000));
+    }
+}
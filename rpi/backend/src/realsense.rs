@@ -0,0 +1,94 @@
+use realsense_rust::{
+    context::Context, frame::{ColorFrame, DepthFrame, PixelKind}, kind::{Rs2Format, Rs2StreamKind}, pipeline::{ActivePipeline, InactivePipeline}, processing_blocks::align::Align
+};
+use std::time::Duration;
+
+use crate::camera::{encode_color_frame, encode_depth_frame};
+use crate::config::CONFIG;
+use crate::hardware::CameraSource;
+
+pub struct RealSenseCamera {
+    _context: Context,
+    pipeline: ActivePipeline,
+    align: Align,
+}
+
+impl RealSenseCamera {
+    pub fn new() -> Result<Self, String> {
+        let width = CONFIG.camera_width as usize;
+        let height = CONFIG.camera_height as usize;
+        let fps = CONFIG.camera_fps as usize;
+
+        let mut config = realsense_rust::config::Config::new();
+        config
+            .enable_stream(Rs2StreamKind::Color, None, width, height, Rs2Format::Bgr8, fps).map_err(|e| e.to_string())?
+            .enable_stream(Rs2StreamKind::Depth, None, width, height, Rs2Format::Z16, fps).map_err(|e| e.to_string())?;
+
+        let context = Context::new().map_err(|e| e.to_string())?;
+        let pipeline = InactivePipeline::try_from(&context).map_err(|e| e.to_string())?;
+        let pipeline = pipeline.start(Some(config)).map_err(|e| e.to_string())?;
+
+        let align = Align::new(Rs2StreamKind::Color, 10).map_err(|e| format!("Failed to create align block: {}", e))?;
+
+        Ok(Self { _context: context, pipeline, align })
+    }
+}
+
+impl CameraSource for RealSenseCamera {
+    fn next_frames(&mut self) -> Result<(Vec<u8>, Vec<u8>), String> {
+        loop {
+            let timeout = Duration::from_millis(5000);
+            let frames = self.pipeline.wait(Some(timeout)).map_err(|e| e.to_string())?;
+
+            self.align.queue(frames).map_err(|e| e.to_string())?;
+            let aligned_frames = match self.align.wait(Duration::from_millis(100)) {
+                Ok(f) => f,
+                Err(_) => continue,
+            };
+
+            let mut color_frames = aligned_frames.frames_of_type::<ColorFrame>();
+            let mut depth_frames = aligned_frames.frames_of_type::<DepthFrame>();
+
+            if color_frames.is_empty() || depth_frames.is_empty() {
+                continue;
+            }
+
+            let color_frame = color_frames.pop().unwrap();
+            let depth_frame = depth_frames.pop().unwrap();
+
+            return Ok((color_frame_data(&color_frame), depth_frame_data(&depth_frame)));
+        }
+    }
+}
+
+fn color_frame_data(color_frame: &ColorFrame) -> Vec<u8> {
+    let width = color_frame.width();
+    let height = color_frame.height();
+
+    let mut bgr = vec![0u8; width * height * 3];
+    for y in 0..height {
+        for x in 0..width {
+            if let Some(PixelKind::Bgr8 { b, g, r }) = color_frame.get(x, y) {
+                let i = (y * width + x) * 3;
+                bgr[i..i + 3].copy_from_slice(&[*b, *g, *r]);
+            }
+        }
+    }
+
+    encode_color_frame(width as u32, height as u32, &bgr)
+}
+
+fn depth_frame_data(depth_frame: &DepthFrame) -> Vec<u8> {
+    let width = depth_frame.width();
+    let height = depth_frame.height();
+
+    // Get the multiplier to convert raw units to meters (millimeters to meters: 0.001)
+    let units = depth_frame.depth_units().unwrap_or(0.001);
+
+    let raw_data: &[u16] = unsafe {
+        let ptr = depth_frame.get_data() as *const _ as *const u16;
+        std::slice::from_raw_parts(ptr, width * height)
+    };
+
+    encode_depth_frame(width as u32, height as u32, units, raw_data)
+}
@@ -5,6 +5,8 @@ use serde_json::json;
 use tokio::{fs::File, sync::Mutex, io::AsyncReadExt};
 use zip::{ZipWriter, write::FileOptions};
 
+use crate::config::CONFIG;
+
 // Shared state for recording
 pub static COLOR_FRAMES: Lazy<Mutex<Option<Vec<Vec<u8>>>>> = Lazy::new(|| {
     Mutex::new(None)
@@ -66,14 +68,15 @@ fn save_video(frames: Vec<Vec<u8>>, output_path: PathBuf) -> ExitStatus {
     }
 
     let output_str: &str = output_path.to_str().unwrap();
+    let fps = CONFIG.camera_fps.to_string();
     let mut ffmpeg: std::process::Child = Command::new("ffmpeg")
-        .args(&[
+        .args([
             "-y",
             "-f", "image2pipe",
             "-i", "-",
             "-c:v", "libx264",
             "-pix_fmt", "yuv420p",
-            "-r", "15",
+            "-r", &fps,
             output_str
         ])
         .stdin(Stdio::piped())
@@ -88,8 +91,7 @@ fn save_video(frames: Vec<Vec<u8>>, output_path: PathBuf) -> ExitStatus {
         }
     }
 
-    let status = ffmpeg.wait().expect("Failed to wait for ffmpeg");
-    status
+    ffmpeg.wait().expect("Failed to wait for ffmpeg")
 }
 
 pub async fn download_recordings() -> Result<impl IntoResponse, StatusCode> {
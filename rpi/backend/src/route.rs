@@ -0,0 +1,374 @@
+use std::path::PathBuf;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::Json;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::command::{CommandError, MotionCommand};
+use crate::config::CONFIG;
+use crate::mecanum::WheelSpeeds;
+use crate::ramp;
+use crate::script::{self, MotionScript, ScriptProgress, ScriptStep};
+
+// Route being recorded, if any
+static RECORDER: Lazy<Mutex<Option<Recorder>>> = Lazy::new(|| Mutex::new(None));
+
+struct Recorder {
+    name: String,
+    started: Instant,
+    speed: i32,
+    commands: Vec<RouteCommand>,
+}
+
+/// Motion commands sent while driving, replayable with the same timing
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Route {
+    pub name: String,
+    /// Length of the recording, the last command is held until then
+    pub duration_ms: u64,
+    /// Speed setting when the recording started, for the motions before any `speed` command
+    pub speed: i32,
+    pub commands: Vec<RouteCommand>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RouteCommand {
+    /// Time since the recording started
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub command: MotionCommand,
+}
+
+// Same motion on the other side of the robot's forward axis
+fn mirror(command: MotionCommand) -> MotionCommand {
+    match command {
+        MotionCommand::Left => MotionCommand::Right,
+        MotionCommand::Right => MotionCommand::Left,
+        MotionCommand::RotateLeft => MotionCommand::RotateRight,
+        MotionCommand::RotateRight => MotionCommand::RotateLeft,
+        // Negating vy and omega in the mecanum mix swaps the left and right wheels
+        MotionCommand::Wheels(w) => MotionCommand::Wheels(WheelSpeeds { lf: w.rf, lb: w.rb, rf: w.lf, rb: w.lb }),
+        other => other,
+    }
+}
+
+// Opposite motion, undoing the original when held as long
+fn invert(command: MotionCommand) -> MotionCommand {
+    match command {
+        MotionCommand::Forward => MotionCommand::Backward,
+        MotionCommand::Backward => MotionCommand::Forward,
+        MotionCommand::Left => MotionCommand::Right,
+        MotionCommand::Right => MotionCommand::Left,
+        MotionCommand::RotateLeft => MotionCommand::RotateRight,
+        MotionCommand::RotateRight => MotionCommand::RotateLeft,
+        MotionCommand::Wheels(w) => MotionCommand::Wheels(WheelSpeeds { lf: -w.lf, lb: -w.lb, rf: -w.rf, rb: -w.rb }),
+        other => other,
+    }
+}
+
+impl Route {
+    /// Turns the route into a script, each command held until the next one. It
+    /// starts with the speed setting, which may differ from the one at replay.
+    pub fn to_script(&self, mirrored: bool, reversed: bool) -> MotionScript {
+        let mut steps = vec![ScriptStep { command: MotionCommand::Speed { value: self.speed }, duration_ms: 0 }];
+        steps.extend(self.commands.iter().enumerate().map(|(i, c)| {
+            let end = self.commands.get(i + 1).map_or(self.duration_ms, |next| next.at_ms);
+            let command = if mirrored { mirror(c.command) } else { c.command };
+            ScriptStep { command, duration_ms: end.saturating_sub(c.at_ms) }
+        }));
+
+        if reversed {
+            steps = reverse(steps);
+        }
+        MotionScript { name: format!("route {}", self.name), steps }
+    }
+}
+
+// Drives the steps backwards. `speed` only applies to the motions sent after
+// it, so each motion is preceded by the speed it originally ran at. The steps
+// start with a `speed`, so the reversed ones do too.
+fn reverse(steps: Vec<ScriptStep>) -> Vec<ScriptStep> {
+    let mut speed = None;
+    let mut motions: Vec<(Option<i32>, ScriptStep)> = Vec::new();
+    for step in steps {
+        match step.command {
+            MotionCommand::Speed { value } => {
+                speed = Some(value);
+                // The previous motion kept going meanwhile
+                if let Some((_, previous)) = motions.last_mut() {
+                    previous.duration_ms += step.duration_ms;
+                }
+            }
+            _ => motions.push((speed, step)),
+        }
+    }
+
+    let mut reversed = Vec::new();
+    let mut current_speed = None;
+    for (speed, step) in motions.into_iter().rev() {
+        if let Some(value) = speed.filter(|&s| Some(s) != current_speed) {
+            reversed.push(ScriptStep { command: MotionCommand::Speed { value }, duration_ms: 0 });
+            current_speed = Some(value);
+        }
+        reversed.push(ScriptStep { command: invert(step.command), duration_ms: step.duration_ms });
+    }
+    reversed
+}
+
+/// Adds a line written to the Arduino to the route being recorded, if it is a
+/// motion command. Commands of a running script are left out, so a route
+/// replayed while recording isn't driven twice when the new one is replayed.
+pub async fn record(line: &str) {
+    if script::is_running().await {
+        return;
+    }
+    let mut recorder = RECORDER.lock().await;
+    let Some(recorder) = recorder.as_mut() else {
+        return;
+    };
+    if let Ok(command) = line.parse::<MotionCommand>() {
+        let at_ms = recorder.started.elapsed().as_millis() as u64;
+        recorder.commands.push(RouteCommand { at_ms, command });
+    }
+}
+
+fn routes_dir() -> PathBuf {
+    CONFIG.data_dir.join("routes")
+}
+
+// Names end up in file names, so keep them simple
+fn route_path(name: &str) -> Result<PathBuf, (StatusCode, Json<CommandError>)> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(CommandError::new("invalid_name", "Route names may only contain letters, digits, '-' and '_'".to_string())),
+        ));
+    }
+    Ok(routes_dir().join(format!("{}.json", name)))
+}
+
+fn storage_error(e: impl std::fmt::Display) -> (StatusCode, Json<CommandError>) {
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(CommandError::new("storage_error", e.to_string())))
+}
+
+async fn load_route(name: &str) -> Result<Route, (StatusCode, Json<CommandError>)> {
+    let path = route_path(name)?;
+    let data = tokio::fs::read(&path).await.map_err(|_| {
+        (StatusCode::NOT_FOUND, Json(CommandError::new("unknown_route", format!("No route named '{}'", name))))
+    })?;
+    serde_json::from_slice(&data).map_err(storage_error)
+}
+
+#[derive(Deserialize)]
+pub struct RecordRouteRequest {
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct RecorderStatus {
+    recording: bool,
+    name: Option<String>,
+    commands: usize,
+    elapsed_ms: u64,
+}
+
+pub async fn recorder_status() -> Json<RecorderStatus> {
+    let recorder = RECORDER.lock().await;
+    Json(match recorder.as_ref() {
+        Some(r) => RecorderStatus {
+            recording: true,
+            name: Some(r.name.clone()),
+            commands: r.commands.len(),
+            elapsed_ms: r.started.elapsed().as_millis() as u64,
+        },
+        None => RecorderStatus { recording: false, name: None, commands: 0, elapsed_ms: 0 },
+    })
+}
+
+pub async fn start_route_recording(Json(payload): Json<RecordRouteRequest>) -> Result<Json<RecorderStatus>, (StatusCode, Json<CommandError>)> {
+    route_path(&payload.name)?;
+    let speed = ramp::speed().await;
+    {
+        let mut recorder = RECORDER.lock().await;
+        if recorder.is_some() {
+            return Err((StatusCode::CONFLICT, Json(CommandError::new("already_recording", "A route is already being recorded".to_string()))));
+        }
+        *recorder = Some(Recorder { name: payload.name, started: Instant::now(), speed, commands: Vec::new() });
+    }
+    Ok(recorder_status().await)
+}
+
+pub async fn stop_route_recording() -> Result<Json<RouteSummary>, (StatusCode, Json<CommandError>)> {
+    let recorder = RECORDER.lock().await.take().ok_or_else(|| {
+        (StatusCode::BAD_REQUEST, Json(CommandError::new("not_recording", "No route is being recorded".to_string())))
+    })?;
+
+    let route = Route {
+        name: recorder.name,
+        duration_ms: recorder.started.elapsed().as_millis() as u64,
+        speed: recorder.speed,
+        commands: recorder.commands,
+    };
+
+    let path = route_path(&route.name)?;
+    tokio::fs::create_dir_all(routes_dir()).await.map_err(storage_error)?;
+    let data = serde_json::to_vec_pretty(&route).map_err(storage_error)?;
+    tokio::fs::write(&path, data).await.map_err(storage_error)?;
+
+    Ok(Json(RouteSummary::of(&route)))
+}
+
+#[derive(Serialize)]
+pub struct RouteSummary {
+    name: String,
+    duration_ms: u64,
+    commands: usize,
+}
+
+impl RouteSummary {
+    fn of(route: &Route) -> Self {
+        Self { name: route.name.clone(), duration_ms: route.duration_ms, commands: route.commands.len() }
+    }
+}
+
+pub async fn list_routes() -> Result<Json<Vec<RouteSummary>>, (StatusCode, Json<CommandError>)> {
+    let mut entries = match tokio::fs::read_dir(routes_dir()).await {
+        Ok(entries) => entries,
+        // Nothing recorded yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Json(Vec::new())),
+        Err(e) => return Err(storage_error(e)),
+    };
+
+    let mut routes = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(storage_error)? {
+        let path = entry.path();
+        let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        match load_route(name).await {
+            Ok(route) => routes.push(RouteSummary::of(&route)),
+            Err(_) => eprintln!("Skipping unreadable route file {}", path.display()),
+        }
+    }
+    routes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(routes))
+}
+
+pub async fn get_route(Path(name): Path<String>) -> Result<Json<Route>, (StatusCode, Json<CommandError>)> {
+    load_route(&name).await.map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct ReplayRouteRequest {
+    name: String,
+    /// Swap left and right
+    #[serde(default)]
+    mirrored: bool,
+    /// Drive the route backwards, from its end to its start
+    #[serde(default)]
+    reversed: bool,
+}
+
+/// Replays a route as a motion script, controlled and monitored with the `/script` endpoints
+pub async fn replay_route(Json(payload): Json<ReplayRouteRequest>) -> Result<Json<ScriptProgress>, (StatusCode, Json<CommandError>)> {
+    let route = load_route(&payload.name).await?;
+    if route.commands.is_empty() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, Json(CommandError::new("empty_route", format!("Route '{}' has no commands", route.name)))));
+    }
+    script::start(route.to_script(payload.mirrored, payload.reversed)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHEELS: WheelSpeeds = WheelSpeeds { lf: 100, lb: 200, rf: 300, rb: 400 };
+
+    fn route(commands: &[(u64, MotionCommand)]) -> Route {
+        Route {
+            name: "test".to_string(),
+            duration_ms: 3000,
+            speed: 800,
+            commands: commands.iter().map(|&(at_ms, command)| RouteCommand { at_ms, command }).collect(),
+        }
+    }
+
+    fn steps(script: &MotionScript) -> Vec<(MotionCommand, u64)> {
+        script.steps.iter().map(|s| (s.command, s.duration_ms)).collect()
+    }
+
+    #[test]
+    fn mirror_swaps_sides() {
+        assert_eq!(mirror(MotionCommand::Left), MotionCommand::Right);
+        assert_eq!(mirror(MotionCommand::RotateRight), MotionCommand::RotateLeft);
+        assert_eq!(mirror(MotionCommand::Forward), MotionCommand::Forward);
+        assert_eq!(mirror(MotionCommand::Speed { value: 500 }), MotionCommand::Speed { value: 500 });
+        assert_eq!(mirror(MotionCommand::Wheels(WHEELS)), MotionCommand::Wheels(WheelSpeeds { lf: 300, lb: 400, rf: 100, rb: 200 }));
+    }
+
+    #[test]
+    fn mirror_matches_the_firmware_motions() {
+        for command in [MotionCommand::Left, MotionCommand::Right, MotionCommand::RotateLeft, MotionCommand::RotateRight] {
+            let wheels = command.wheel_speeds(1000).unwrap();
+            assert_eq!(mirror(MotionCommand::Wheels(wheels)).wheel_speeds(1000), mirror(command).wheel_speeds(1000));
+        }
+    }
+
+    #[test]
+    fn invert_undoes_the_motion() {
+        for command in [MotionCommand::Forward, MotionCommand::Left, MotionCommand::RotateLeft, MotionCommand::Wheels(WHEELS)] {
+            let wheels = command.wheel_speeds(1000).unwrap();
+            let inverted = invert(command).wheel_speeds(1000).unwrap();
+            assert_eq!(inverted, WheelSpeeds { lf: -wheels.lf, lb: -wheels.lb, rf: -wheels.rf, rb: -wheels.rb });
+            assert_eq!(invert(invert(command)), command);
+        }
+        assert_eq!(invert(MotionCommand::Stop), MotionCommand::Stop);
+    }
+
+    #[test]
+    fn holds_each_command_until_the_next() {
+        let route = route(&[(500, MotionCommand::Forward), (1500, MotionCommand::Left), (2000, MotionCommand::Stop)]);
+        assert_eq!(
+            steps(&route.to_script(false, false)),
+            [
+                (MotionCommand::Speed { value: 800 }, 0),
+                (MotionCommand::Forward, 1000),
+                (MotionCommand::Left, 500),
+                (MotionCommand::Stop, 1000),
+            ],
+        );
+        assert_eq!(steps(&route.to_script(true, false))[2], (MotionCommand::Right, 500));
+    }
+
+    #[test]
+    fn reverses_with_the_original_speeds() {
+        let route = route(&[
+            (0, MotionCommand::Forward),
+            (1000, MotionCommand::Speed { value: 1500 }),
+            (1200, MotionCommand::RotateLeft),
+            (2000, MotionCommand::Stop),
+        ]);
+        assert_eq!(
+            steps(&route.to_script(false, true)),
+            [
+                (MotionCommand::Speed { value: 1500 }, 0),
+                (MotionCommand::Stop, 1000),
+                (MotionCommand::RotateRight, 800),
+                (MotionCommand::Speed { value: 800 }, 0),
+                // Still going forward while the speed was changed
+                (MotionCommand::Backward, 1200),
+            ],
+        );
+    }
+
+    #[test]
+    fn reversed_script_starts_with_the_recorded_speed() {
+        let route = route(&[(0, MotionCommand::Forward), (1000, MotionCommand::Stop)]);
+        let reversed = steps(&route.to_script(true, true));
+        assert_eq!(reversed[0], (MotionCommand::Speed { value: 800 }, 0));
+        assert_eq!(reversed[1..], [(MotionCommand::Stop, 2000), (MotionCommand::Backward, 1000)]);
+    }
+}
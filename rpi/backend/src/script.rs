@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use axum::http::StatusCode;
+use axum::response::Json;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tokio::sync::{Mutex, watch};
+use tokio::time::Instant;
+
+use crate::command::{CommandError, MotionCommand};
+use crate::serial::{SerialError, write_line, write_line_unchecked};
+use crate::watchdog;
+
+// Limits on uploaded scripts
+const MAX_STEPS: usize = 1000;
+const MAX_STEP_DURATION_MS: u64 = 10 * 60 * 1000;
+
+// The runner counts as the operator for the watchdog while a script runs
+const FEED_INTERVAL: Duration = Duration::from_millis(200);
+
+// Uploaded scripts, by name
+static SCRIPTS: Lazy<Mutex<HashMap<String, MotionScript>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static RUN: Lazy<Mutex<ScriptRun>> = Lazy::new(|| {
+    Mutex::new(ScriptRun { progress: ScriptProgress::default(), control: None })
+});
+
+/// A sequence of motion commands, each held for a duration, e.g.
+/// `{"name": "square", "steps": [{"command": "forward", "duration_ms": 2000}, {"command": "rotate_right", "duration_ms": 1000}]}`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MotionScript {
+    pub name: String,
+    pub steps: Vec<ScriptStep>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScriptStep {
+    #[serde(flatten)]
+    pub command: MotionCommand,
+    /// How long to keep the command before the next step
+    #[serde(default)]
+    pub duration_ms: u64,
+}
+
+impl MotionScript {
+    fn validate(&self) -> Result<(), CommandError> {
+        if self.name.trim().is_empty() {
+            return Err(CommandError::new("invalid_script", "The script needs a name".to_string()));
+        }
+        if self.steps.is_empty() || self.steps.len() > MAX_STEPS {
+            return Err(CommandError::new("invalid_script", format!("A script needs 1 to {} steps", MAX_STEPS)));
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            step.command.validate().map_err(|e| CommandError::new(e.error, format!("Step {}: {}", i + 1, e.message)))?;
+            if step.duration_ms > MAX_STEP_DURATION_MS {
+                return Err(CommandError::new(
+                    "invalid_script",
+                    format!("Step {}: duration_ms must be at most {}", i + 1, MAX_STEP_DURATION_MS),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptState {
+    #[default]
+    Idle,
+    Running,
+    Paused,
+    Finished,
+    Aborted,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct ScriptProgress {
+    state: ScriptState,
+    name: Option<String>,
+    /// Index of the current step, from 0
+    step: usize,
+    steps: usize,
+    command: Option<MotionCommand>,
+    /// Time left in the current step
+    step_remaining_ms: u64,
+    error: Option<String>,
+}
+
+// Requested by the endpoints, followed by the runner
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Control {
+    Run,
+    Pause,
+    Abort,
+}
+
+struct ScriptRun {
+    progress: ScriptProgress,
+    control: Option<watch::Sender<Control>>,
+}
+
+// Where the runner sends its commands, replaced in the tests
+trait CommandWriter {
+    /// Writes a command line, first waiting for the Arduino to be ready if `check_ready`
+    async fn write(&mut self, line: &str, check_ready: bool) -> Result<(), SerialError>;
+}
+
+struct SerialWriter;
+
+impl CommandWriter for SerialWriter {
+    async fn write(&mut self, line: &str, check_ready: bool) -> Result<(), SerialError> {
+        if check_ready { write_line(line).await? } else { write_line_unchecked(line).await? };
+        Ok(())
+    }
+}
+
+async fn update_progress(update: impl FnOnce(&mut ScriptProgress)) {
+    update(&mut RUN.lock().await.progress);
+}
+
+/// Runs the script, always ending with `stop`
+async fn run_script(script: MotionScript, mut control: watch::Receiver<Control>, writer: &mut impl CommandWriter) {
+    let result = run_steps(&script, &mut control, writer).await;
+
+    if let Err(e) = writer.write("stop", true).await {
+        eprintln!("Script '{}': failed to send stop: {}", script.name, e);
+    }
+
+    let mut run = RUN.lock().await;
+    run.control = None;
+    let progress = &mut run.progress;
+    progress.command = Some(MotionCommand::Stop);
+    progress.step_remaining_ms = 0;
+    progress.state = match result {
+        Ok(true) => ScriptState::Finished,
+        Ok(false) => ScriptState::Aborted,
+        Err(e) => {
+            progress.error = Some(e.to_string());
+            ScriptState::Failed
+        }
+    };
+}
+
+/// Returns whether the script ran to the end, `false` if it was aborted
+async fn run_steps(
+    script: &MotionScript,
+    control: &mut watch::Receiver<Control>,
+    writer: &mut impl CommandWriter,
+) -> Result<bool, SerialError> {
+    // Only the first command waits for the Arduino to be ready, to keep the timing
+    let mut ready = false;
+
+    // Each step starts when the previous one should have ended, so the time
+    // spent writing commands doesn't add up over long scripts
+    let mut step_start = None;
+
+    for (i, step) in script.steps.iter().enumerate() {
+        let line = step.command.to_string();
+        writer.write(&line, !ready).await?;
+        ready = true;
+
+        let mut deadline = step_start.unwrap_or_else(Instant::now) + Duration::from_millis(step.duration_ms);
+        update_progress(|p| {
+            p.step = i;
+            p.command = Some(step.command);
+            p.step_remaining_ms = step.duration_ms;
+        })
+        .await;
+
+        let mut feed = tokio::time::interval(FEED_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => {
+                    step_start = Some(deadline);
+                    break;
+                }
+                _ = feed.tick() => {
+                    watchdog::feed().await;
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    update_progress(|p| p.step_remaining_ms = remaining.as_millis() as u64).await;
+                }
+                changed = control.changed() => {
+                    if changed.is_err() {
+                        return Ok(false);
+                    }
+                    let requested = *control.borrow_and_update();
+                    match requested {
+                        Control::Abort => return Ok(false),
+                        Control::Run => continue,
+                        Control::Pause => {}
+                    }
+
+                    // Hold still until resumed, then carry on with the rest of the step
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    writer.write("stop", false).await?;
+                    update_progress(|p| {
+                        p.state = ScriptState::Paused;
+                        p.step_remaining_ms = remaining.as_millis() as u64;
+                    })
+                    .await;
+
+                    let resumed = matches!(control.wait_for(|c| *c != Control::Pause).await.as_deref(), Ok(Control::Run));
+                    if !resumed {
+                        return Ok(false);
+                    }
+                    writer.write(&line, true).await?;
+                    deadline = Instant::now() + remaining;
+                    update_progress(|p| p.state = ScriptState::Running).await;
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+pub async fn list_scripts() -> Json<Vec<MotionScript>> {
+    let scripts = SCRIPTS.lock().await;
+    let mut scripts: Vec<MotionScript> = scripts.values().cloned().collect();
+    scripts.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(scripts)
+}
+
+pub async fn upload_script(Json(script): Json<MotionScript>) -> Result<Json<MotionScript>, (StatusCode, Json<CommandError>)> {
+    script.validate().map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, Json(e)))?;
+    SCRIPTS.lock().await.insert(script.name.clone(), script.clone());
+    Ok(Json(script))
+}
+
+#[derive(Deserialize)]
+pub struct StartScriptRequest {
+    name: String,
+}
+
+pub async fn start_script(Json(payload): Json<StartScriptRequest>) -> Result<Json<ScriptProgress>, (StatusCode, Json<CommandError>)> {
+    let script = SCRIPTS.lock().await.get(&payload.name).cloned().ok_or_else(|| {
+        (StatusCode::NOT_FOUND, Json(CommandError::new("unknown_script", format!("No script named '{}'", payload.name))))
+    })?;
+    start(script).await
+}
+
+/// Starts running a script unless one is already running
+pub async fn start(script: MotionScript) -> Result<Json<ScriptProgress>, (StatusCode, Json<CommandError>)> {
+    let mut run = RUN.lock().await;
+    if run.control.is_some() {
+        return Err((StatusCode::CONFLICT, Json(CommandError::new("script_running", "A script is already running".to_string()))));
+    }
+
+    let (sender, receiver) = watch::channel(Control::Run);
+    run.control = Some(sender);
+    run.progress = ScriptProgress {
+        state: ScriptState::Running,
+        name: Some(script.name.clone()),
+        steps: script.steps.len(),
+        ..Default::default()
+    };
+    tokio::spawn(async move { run_script(script, receiver, &mut SerialWriter).await });
+
+    Ok(Json(run.progress.clone()))
+}
+
+async fn set_control(control: Control) -> Result<Json<ScriptProgress>, (StatusCode, Json<CommandError>)> {
+    {
+        let run = RUN.lock().await;
+        match &run.control {
+            Some(sender) => {
+                sender.send_replace(control);
+            }
+            None => {
+                return Err((StatusCode::BAD_REQUEST, Json(CommandError::new("not_running", "No script is running".to_string()))));
+            }
+        }
+    }
+    Ok(script_progress().await)
+}
+
+pub async fn pause_script() -> Result<Json<ScriptProgress>, (StatusCode, Json<CommandError>)> {
+    set_control(Control::Pause).await
+}
+
+pub async fn resume_script() -> Result<Json<ScriptProgress>, (StatusCode, Json<CommandError>)> {
+    set_control(Control::Run).await
+}
+
+pub async fn abort_script() -> Result<Json<ScriptProgress>, (StatusCode, Json<CommandError>)> {
+    set_control(Control::Abort).await
+}
+
+/// Whether a script or replayed route is running
+pub async fn is_running() -> bool {
+    RUN.lock().await.control.is_some()
+}
+
+pub async fn script_progress() -> Json<ScriptProgress> {
+    Json(RUN.lock().await.progress.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records the lines written, with the milliseconds since it was created
+    struct Recorder {
+        start: Instant,
+        lines: Vec<(u64, String, bool)>,
+    }
+
+    impl Recorder {
+        fn new() -> Self {
+            Self { start: Instant::now(), lines: Vec::new() }
+        }
+    }
+
+    impl CommandWriter for Recorder {
+        async fn write(&mut self, line: &str, check_ready: bool) -> Result<(), SerialError> {
+            self.lines.push((self.start.elapsed().as_millis() as u64, line.to_string(), check_ready));
+            Ok(())
+        }
+    }
+
+    fn script() -> MotionScript {
+        serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "steps": [{"command": "forward", "duration_ms": 1000}, {"command": "left", "duration_ms": 500}],
+        }))
+        .unwrap()
+    }
+
+    fn written(lines: &[(u64, String, bool)]) -> Vec<(u64, &str, bool)> {
+        lines.iter().map(|(t, line, check_ready)| (*t, line.as_str(), *check_ready)).collect()
+    }
+
+    // Runs the steps while `control` sends what is requested
+    async fn run(control: impl AsyncFnOnce(&watch::Sender<Control>)) -> (Result<bool, SerialError>, Recorder) {
+        let (sender, mut receiver) = watch::channel(Control::Run);
+        let mut recorder = Recorder::new();
+        let script = script();
+        let (result, ()) = tokio::join!(run_steps(&script, &mut receiver, &mut recorder), control(&sender));
+        (result, recorder)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn runs_the_steps_on_time() {
+        let (result, recorder) = run(async |_| {}).await;
+        assert!(result.unwrap());
+        assert_eq!(written(&recorder.lines), [(0, "forward", true), (1000, "left", false)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pausing_stops_until_resumed() {
+        let (result, recorder) = run(async |control| {
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            control.send_replace(Control::Pause);
+            tokio::time::sleep(Duration::from_millis(1000)).await;
+            control.send_replace(Control::Run);
+        })
+        .await;
+        assert!(result.unwrap());
+        // The rest of the first step after resuming, then the second one
+        assert_eq!(
+            written(&recorder.lines),
+            [(0, "forward", true), (400, "stop", false), (1400, "forward", true), (2000, "left", false)],
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn aborting_ends_the_run() {
+        let (result, recorder) = run(async |control| {
+            tokio::time::sleep(Duration::from_millis(1200)).await;
+            control.send_replace(Control::Abort);
+        })
+        .await;
+        assert!(!result.unwrap());
+        assert_eq!(written(&recorder.lines), [(0, "forward", true), (1000, "left", false)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn aborting_while_paused_ends_the_run() {
+        let (result, recorder) = run(async |control| {
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            control.send_replace(Control::Pause);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            control.send_replace(Control::Abort);
+        })
+        .await;
+        assert!(!result.unwrap());
+        assert_eq!(written(&recorder.lines), [(0, "forward", true), (400, "stop", false)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn always_ends_with_stop() {
+        let (sender, receiver) = watch::channel(Control::Run);
+        let mut recorder = Recorder::new();
+        let run = run_script(script(), receiver, &mut recorder);
+        let abort = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            sender.send_replace(Control::Abort);
+        };
+        tokio::join!(run, abort);
+        assert_eq!(written(&recorder.lines), [(0, "forward", true), (300, "stop", true)]);
+
+        let (_sender, receiver) = watch::channel(Control::Run);
+        let mut recorder = Recorder::new();
+        run_script(script(), receiver, &mut recorder).await;
+        assert_eq!(recorder.lines.last().map(|(t, line, _)| (*t, line.as_str())), Some((1500, "stop")));
+    }
+}
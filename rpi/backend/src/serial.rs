@@ -1,19 +1,39 @@
-use axum::{extract::State, response::Json};
+use axum::response::{IntoResponse, Json, Response};
 use axum::http::StatusCode;
 use serde::{Serialize, Deserialize};
-use serialport::{SerialPort, DataBits, FlowControl, Parity, StopBits};
+use serialport::{DataBits, FlowControl, Parity, StopBits};
 use once_cell::sync::Lazy;
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, broadcast};
 use std::sync::Arc;
 
-use crate::mpu6050::MPU6050;
+use crate::command::{CommandError, MotionCommand};
+use crate::config::CONFIG;
+use crate::connection::{CONNECTION, ConnectionState, DeviceIdentity};
+use crate::firmware::{FirmwareInfo, handshake};
+use crate::framing::{FRAME_REPLIES, FrameReply, Protocol, encode_command, next_seq};
+use crate::hardware::{MotorLink, SIMULATED_PORT_PATH, open_simulated_motor_link};
+use crate::ramp;
+use crate::route;
+use crate::serial_reader::{SERIAL_LINES, SerialLine, collect_replies};
+use crate::virtual_arduino::VIRTUAL_ARDUINO_PORT;
+use crate::watchdog;
 
 // Global serial port instance
-static SERIAL_PORT: Lazy<Arc<Mutex<Option<Box<dyn SerialPort>>>>> = Lazy::new(|| {
+static SERIAL_PORT: Lazy<Arc<Mutex<Port>>> = Lazy::new(|| {
     Arc::new(Mutex::new(None))
 });
 
+type Port = Option<Box<dyn MotorLink>>;
+type PortGuard<'a> = tokio::sync::MutexGuard<'a, Port>;
+
+// How long to wait for the acknowledgement of a framed command, and how often to send it
+const ACK_TIMEOUT: Duration = Duration::from_millis(200);
+const ACK_ATTEMPTS: u32 = 3;
+
+// Longest wait for the replies to a command sent with `/send`
+const MAX_REPLY_TIMEOUT_MS: u64 = 10_000;
+
 #[derive(Serialize)]
 pub struct SerialDeviceInfo {
     port_name: String,
@@ -30,7 +50,7 @@ pub async fn list_serial_devices() -> Json<Vec<SerialDeviceInfo>> {
         Err(_) => return Json(vec![]),
     };
 
-    let serial_devices: Vec<SerialDeviceInfo> = ports
+    let mut serial_devices: Vec<SerialDeviceInfo> = ports
         .into_iter()
         .map(|p| match p.port_type {
             serialport::SerialPortType::UsbPort(info) => SerialDeviceInfo {
@@ -52,45 +72,242 @@ pub async fn list_serial_devices() -> Json<Vec<SerialDeviceInfo>> {
         })
         .collect();
 
+    if let Some(port_path) = VIRTUAL_ARDUINO_PORT.get() {
+        serial_devices.push(SerialDeviceInfo {
+            port_name: port_path.clone(),
+            vid: None,
+            pid: None,
+            serial_number: None,
+            manufacturer: None,
+            product: Some("Virtual Arduino".to_string()),
+        });
+    }
+
+    if CONFIG.simulation {
+        serial_devices.push(SerialDeviceInfo {
+            port_name: SIMULATED_PORT_PATH.to_string(),
+            vid: None,
+            pid: None,
+            serial_number: None,
+            manufacturer: None,
+            product: Some("Simulated Arduino".to_string()),
+        });
+    }
+
     Json(serial_devices)
 }
 
+/// Line settings of the serial port, 115200 baud 8N1 by default as in the firmware
+#[derive(Clone, Copy, Debug)]
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    pub timeout: Duration,
+    pub protocol: Protocol,
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        Self {
+            baud_rate: 115200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            timeout: Duration::from_millis(100),
+            protocol: CONFIG.serial_protocol,
+        }
+    }
+}
+
+// Same field names and values as accepted by `/connect`
+impl Serialize for SerialSettings {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("SerialSettings", 7)?;
+        state.serialize_field("baud_rate", &self.baud_rate)?;
+        state.serialize_field("data_bits", &u8::from(self.data_bits))?;
+        state.serialize_field("parity", &self.parity.to_string().to_lowercase())?;
+        state.serialize_field("stop_bits", &u8::from(self.stop_bits))?;
+        state.serialize_field("flow_control", &self.flow_control.to_string().to_lowercase())?;
+        state.serialize_field("timeout_ms", &(self.timeout.as_millis() as u64))?;
+        state.serialize_field("protocol", &self.protocol)?;
+        state.end()
+    }
+}
+
+/// Settings given to `/connect`, missing ones keep their default
+#[derive(Deserialize, Default)]
+pub struct SerialSettingsRequest {
+    baud_rate: Option<u32>,
+    data_bits: Option<u8>,
+    parity: Option<String>,
+    stop_bits: Option<u8>,
+    flow_control: Option<String>,
+    timeout_ms: Option<u64>,
+    protocol: Option<String>,
+}
+
+impl SerialSettingsRequest {
+    pub fn settings(&self) -> Result<SerialSettings, String> {
+        let mut settings = SerialSettings::default();
+
+        if let Some(baud_rate) = self.baud_rate {
+            if baud_rate == 0 {
+                return Err("baud_rate must be greater than 0".to_string());
+            }
+            settings.baud_rate = baud_rate;
+        }
+        if let Some(data_bits) = self.data_bits {
+            settings.data_bits = DataBits::try_from(data_bits)
+                .map_err(|_| format!("data_bits must be 5, 6, 7 or 8, got {}", data_bits))?;
+        }
+        if let Some(parity) = &self.parity {
+            settings.parity = match parity.to_lowercase().as_str() {
+                "none" => Parity::None,
+                "odd" => Parity::Odd,
+                "even" => Parity::Even,
+                _ => return Err(format!("parity must be none, odd or even, got '{}'", parity)),
+            };
+        }
+        if let Some(stop_bits) = self.stop_bits {
+            settings.stop_bits = StopBits::try_from(stop_bits)
+                .map_err(|_| format!("stop_bits must be 1 or 2, got {}", stop_bits))?;
+        }
+        if let Some(flow_control) = &self.flow_control {
+            settings.flow_control = flow_control.parse()
+                .map_err(|_| format!("flow_control must be none, software or hardware, got '{}'", flow_control))?;
+        }
+        if let Some(timeout_ms) = self.timeout_ms {
+            if timeout_ms == 0 {
+                return Err("timeout_ms must be greater than 0".to_string());
+            }
+            settings.timeout = Duration::from_millis(timeout_ms);
+        }
+        if let Some(protocol) = &self.protocol {
+            settings.protocol = protocol.parse()?;
+        }
+
+        Ok(settings)
+    }
+}
+
 #[derive(Deserialize)]
 pub struct ConnectRequest {
     port_path: String,
+    #[serde(flatten)]
+    settings: SerialSettingsRequest,
+    /// Identify the firmware before accepting the connection (default true);
+    /// turn it off for boards that don't run the carbot firmware
+    handshake: Option<bool>,
+}
+
+fn open_port(port_path: &str, settings: SerialSettings) -> Result<Box<dyn MotorLink>, (StatusCode, String)> {
+    if port_path == SIMULATED_PORT_PATH {
+        return open_simulated_motor_link().map_err(|e| (StatusCode::BAD_REQUEST, e));
+    }
+
+    match serialport::new(port_path, settings.baud_rate)
+        .data_bits(settings.data_bits)
+        .flow_control(settings.flow_control)
+        .parity(settings.parity)
+        .stop_bits(settings.stop_bits)
+        .timeout(settings.timeout)
+        .open()
+    {
+        Ok(p) => Ok(Box::new(p)),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Port error: {}", e))),
+    }
 }
 
 pub async fn connect(Json(payload): Json<ConnectRequest>) -> (StatusCode, String) {
-    let mut port_guard: tokio::sync::MutexGuard<'_, Option<Box<dyn serialport::SerialPort>>> =
-        SERIAL_PORT.lock().await;
+    let port_guard: PortGuard<'_> = SERIAL_PORT.lock().await;
 
     if port_guard.is_some() {
         return (StatusCode::BAD_REQUEST, "Serial port already connected".to_string());
     }
 
-    let port = match serialport::new(&payload.port_path, 115200)
-        .data_bits(DataBits::Eight)
-        .flow_control(FlowControl::None)
-        .parity(Parity::None)
-        .stop_bits(StopBits::One)
-        .timeout(Duration::from_millis(100))
-        .open()
-    {
+    let settings = match payload.settings.settings() {
+        Ok(s) => s,
+        Err(e) => return (StatusCode::BAD_REQUEST, e),
+    };
+
+    let port = match open_port(&payload.port_path, settings) {
         Ok(p) => p,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Port error: {}", e)),
+        Err(e) => return e,
     };
 
-    *port_guard = Some(port);
+    let firmware = match attach(port_guard, port, payload.handshake.unwrap_or(true)).await {
+        Ok(firmware) => firmware,
+        Err(e) => return e,
+    };
+    let settings = adapt_to_firmware(settings, firmware.as_ref());
+    CONNECTION.connected(DeviceIdentity::of(&payload.port_path), settings, firmware).await;
 
     (StatusCode::OK, format!("Connected to {}", payload.port_path))
 }
 
+/// Installs an opened port, identifying the firmware on it if asked to.
+/// The port is closed again if the firmware isn't the expected one.
+async fn attach(mut port_guard: PortGuard<'_>, port: Box<dyn MotorLink>, identify: bool) -> Result<Option<FirmwareInfo>, (StatusCode, String)> {
+    *port_guard = Some(port);
+    drop(port_guard);
+    SERIAL_LINES.reset().await;
+    ramp::reset().await;
+
+    if !identify {
+        return Ok(None);
+    }
+    match handshake().await {
+        Ok(firmware) => Ok(Some(firmware)),
+        Err(e) => {
+            SERIAL_PORT.lock().await.take();
+            Err((e.status(), e.to_string()))
+        }
+    }
+}
+
+// Falls back to what older firmware understands
+fn adapt_to_firmware(mut settings: SerialSettings, firmware: Option<&FirmwareInfo>) -> SerialSettings {
+    if settings.protocol == Protocol::Framed && firmware.is_some_and(|f| !f.has_feature("framed")) {
+        eprintln!("Firmware doesn't support the framed protocol, using the line protocol");
+        settings.protocol = Protocol::Line;
+    }
+    settings
+}
+
+/// Reopens the port after the connection was lost, used by the connection manager.
+/// Identifies the firmware again if it was when connecting.
+pub async fn reattach(port_path: &str, settings: SerialSettings, identify: bool) -> Result<Option<FirmwareInfo>, String> {
+    let port_guard: PortGuard<'_> = SERIAL_PORT.lock().await;
+
+    // Connected by hand in the meantime
+    if port_guard.is_some() {
+        return Err("connected by hand".to_string());
+    }
+
+    let port = open_port(port_path, settings).map_err(|(_, e)| e)?;
+    attach(port_guard, port, identify).await.map_err(|(_, e)| e)
+}
+
+/// Whether a port is open, even if still being identified
+pub async fn is_attached() -> bool {
+    SERIAL_PORT.lock().await.is_some()
+}
+
 pub async fn disconnect() -> (StatusCode, String) {
-    let mut port_guard: tokio::sync::MutexGuard<'_, Option<Box<dyn serialport::SerialPort>>> =
-        SERIAL_PORT.lock().await;
+    let port = SERIAL_PORT.lock().await.take();
+    let reconnecting = matches!(CONNECTION.status().await.state, ConnectionState::Reconnecting { .. });
 
-    if let Some(_port) = port_guard.take() {
+    if port.is_some() || reconnecting {
         // The port will be closed when it goes out of scope
+        drop(port);
+        watchdog::disarm().await;
+        ramp::reset().await;
+        CONNECTION.disconnected().await;
         (StatusCode::OK, "Disconnected from serial port".to_string())
     } else {
         (StatusCode::BAD_REQUEST, "Serial port not connected".to_string())
@@ -100,9 +317,18 @@ pub async fn disconnect() -> (StatusCode, String) {
 #[derive(Deserialize)]
 pub struct SerialMessage {
     message: String,
+    /// Wait up to this long for what the Arduino prints in reply, and return it as JSON
+    reply_timeout_ms: Option<u64>,
 }
 
-async fn wait_for_arduino_ready(port: &mut Box<dyn SerialPort>) -> Result<(), String> {
+#[derive(Serialize)]
+pub struct SendResponse {
+    sent: String,
+    delivery: Delivery,
+    replies: Vec<SerialLine>,
+}
+
+async fn wait_for_arduino_ready(port: &mut Box<dyn MotorLink>) -> Result<(), String> {
     let start = std::time::Instant::now();
     let mut buffer = [0u8; 64];
     
@@ -115,10 +341,10 @@ async fn wait_for_arduino_ready(port: &mut Box<dyn SerialPort>) -> Result<(), St
             tokio::time::sleep(Duration::from_millis(100)).await;
             
             // Check if we can read (indicates Arduino is responsive)
-            if let Ok(n) = port.read(&mut buffer) {
-                if n > 0 {
-                    return Ok(()); // Arduino responded
-                }
+            if let Ok(n) = port.read(&mut buffer) && n > 0 {
+                // Keep anything else the Arduino printed in the meantime
+                SERIAL_LINES.ingest(&buffer[..n]).await;
+                return Ok(()); // Arduino responded
             }
         }
         
@@ -128,38 +354,321 @@ async fn wait_for_arduino_ready(port: &mut Box<dyn SerialPort>) -> Result<(), St
     Err("Timeout waiting for Arduino".to_string())
 }
 
-pub async fn send(Json(payload): Json<SerialMessage>) -> (StatusCode, String) {
-    let mut port_guard: tokio::sync::MutexGuard<'_, Option<Box<dyn serialport::SerialPort>>> =
-        SERIAL_PORT.lock().await;
+#[derive(Debug)]
+pub enum SerialError {
+    NotConnected,
+    NotReady(String),
+    Write(std::io::Error),
+    Read(std::io::Error),
+    /// The firmware answered a framed command with a nack
+    Rejected(String),
+    /// No acknowledgement for a framed command after this many attempts
+    NoAck(u32),
+    /// The firmware doesn't know the command
+    Unsupported(String),
+}
+
+impl SerialError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            SerialError::NotConnected => StatusCode::BAD_REQUEST,
+            SerialError::NotReady(_) | SerialError::Write(_) | SerialError::Read(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            SerialError::Rejected(_) | SerialError::Unsupported(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            SerialError::NoAck(_) => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// Short machine readable name of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            SerialError::NotConnected => "not_connected",
+            SerialError::NotReady(_) => "arduino_not_ready",
+            SerialError::Write(_) => "write_error",
+            SerialError::Read(_) => "read_error",
+            SerialError::Rejected(_) => "command_rejected",
+            SerialError::NoAck(_) => "no_ack",
+            SerialError::Unsupported(_) => "unsupported_command",
+        }
+    }
+
+    /// Whether the port is gone, so the Arduino stopped or will reset on reconnecting
+    pub fn is_port_lost(&self) -> bool {
+        match self {
+            SerialError::NotConnected => true,
+            SerialError::Write(e) | SerialError::Read(e) => is_port_lost(e),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for SerialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerialError::NotConnected => write!(f, "Serial port not connected"),
+            SerialError::NotReady(e) => write!(f, "{}", e),
+            SerialError::Write(e) => write!(f, "Write error: {}", e),
+            SerialError::Read(e) => write!(f, "Read error: {}", e),
+            SerialError::Rejected(reason) => write!(f, "Arduino rejected the command: {}", reason),
+            SerialError::Unsupported(verb) => write!(f, "The firmware doesn't support the '{}' command", verb),
+            SerialError::NoAck(attempts) => write!(f, "No acknowledgement from the Arduino after {} attempts", attempts),
+        }
+    }
+}
+
+/// What is known about a command once `write_line` returns
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivery {
+    /// Written to the port (line protocol)
+    Written,
+    /// Accepted by the firmware (framed protocol)
+    Acknowledged,
+}
+
+/// Writes one command line to the Arduino once it is responsive
+pub async fn write_line(line: &str) -> Result<Delivery, SerialError> {
+    write_line_checked(line, true).await
+}
+
+/// Writes one command line without first pinging the Arduino, for callers
+/// that already know it is responsive and can't afford the extra delay
+pub async fn write_line_unchecked(line: &str) -> Result<Delivery, SerialError> {
+    write_line_checked(line, false).await
+}
+
+// Whether an I/O error means the device is gone, rather than just slow
+fn is_port_lost(e: &std::io::Error) -> bool {
+    !matches!(
+        e.kind(),
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+    )
+}
+
+/// Drops the port after a fatal I/O error so the connection manager can reopen it
+async fn handle_io_error(mut port_guard: PortGuard<'_>, e: &std::io::Error) {
+    if !is_port_lost(e) {
+        return;
+    }
+    *port_guard = None;
+    drop(port_guard);
+    watchdog::disarm().await;
+    // Callers may hold the ramp, which resets itself on the connection event
+    CONNECTION.lost(e.to_string()).await;
+}
+
+async fn write_line_checked(line: &str, check_ready: bool) -> Result<Delivery, SerialError> {
+    let (_, firmware) = CONNECTION.link().await;
+
+    let verb = line.split_whitespace().next().unwrap_or("");
+    if let Some(firmware) = &firmware {
+        // Empty lines are readiness pings, which any firmware answers
+        if !verb.is_empty() && !firmware.supports_command(verb) {
+            return Err(SerialError::Unsupported(verb.to_string()));
+        }
+    }
+
+    let result = match line.parse::<MotionCommand>() {
+        Ok(command) => ramp::drive(command, line, firmware.as_ref(), check_ready).await,
+        Err(_) => deliver(line, check_ready).await,
+    };
+    // Without an ack the command may still have run, only a nack says it didn't
+    if matches!(result, Ok(_) | Err(SerialError::NoAck(_))) {
+        record_written(line).await;
+    }
+    result
+}
+
+/// Writes a line with the protocol of the connection, without any of the
+/// bookkeeping of `write_line`
+pub async fn deliver(line: &str, check_ready: bool) -> Result<Delivery, SerialError> {
+    let (settings, _) = CONNECTION.link().await;
+    if settings.map(|s| s.protocol).unwrap_or_default() == Protocol::Line {
+        write_bytes(format!("{}\n", line).as_bytes(), check_ready).await?;
+        return Ok(Delivery::Written);
+    }
+
+    // The acknowledgement already tells whether the Arduino is responsive
+    write_framed(line).await.map(|_| Delivery::Acknowledged)
+}
+
+// Lets the watchdog and the route recorder know about a command sent to the Arduino
+async fn record_written(line: &str) {
+    watchdog::record_command(line).await;
+    route::record(line).await;
+}
+
+/// Writes raw bytes to the port, without any of the checks of `write_line`
+pub async fn write_bytes(bytes: &[u8], check_ready: bool) -> Result<(), SerialError> {
+    let mut port_guard: PortGuard<'_> = SERIAL_PORT.lock().await;
 
     let port = match &mut *port_guard {
         Some(p) => p,
-        None => return (StatusCode::BAD_REQUEST, "Serial port not connected".to_string()),
+        None => return Err(SerialError::NotConnected),
     };
 
-    if let Err(e) = wait_for_arduino_ready(port).await {
-        return (StatusCode::INTERNAL_SERVER_ERROR, e);
+    if check_ready {
+        wait_for_arduino_ready(port).await.map_err(SerialError::NotReady)?;
     }
 
-    let message = format!("{}\n", payload.message);
-    if let Err(e) = port.write_all(message.as_bytes()) {
-        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Write error: {}", e));
+    if let Err(e) = port.write_all(bytes) {
+        handle_io_error(port_guard, &e).await;
+        return Err(SerialError::Write(e));
     }
+    Ok(())
+}
+
+/// Sends a framed command until the firmware acknowledges it
+async fn write_framed(line: &str) -> Result<(), SerialError> {
+    let seq = next_seq();
+    let frame = format!("{}\n", encode_command(seq, line));
+    // Subscribe before writing so a fast reply can't be missed
+    let mut replies = FRAME_REPLIES.subscribe();
 
-    (StatusCode::OK, format!("Sent '{}'", payload.message))
+    for _ in 0..ACK_ATTEMPTS {
+        write_bytes(frame.as_bytes(), false).await?;
+
+        let reply = tokio::time::timeout(ACK_TIMEOUT, async {
+            loop {
+                match replies.recv().await {
+                    Ok(reply) if reply.seq() == seq => return Some(reply),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .await;
+
+        match reply {
+            Ok(Some(FrameReply::Ack { .. })) => return Ok(()),
+            // Corrupted on the way, the firmware didn't run it
+            Ok(Some(FrameReply::Nack { reason, .. })) if reason == "crc" => continue,
+            Ok(Some(FrameReply::Nack { reason, .. })) => return Err(SerialError::Rejected(reason)),
+            // Lost on the way, or its ack was: the firmware ignores repeated frames
+            Ok(None) | Err(_) => continue,
+        }
+    }
+
+    Err(SerialError::NoAck(ACK_ATTEMPTS))
 }
 
-#[derive(Serialize)]
-pub struct MPU6050Data {
-    accel: (f32, f32, f32),
-    gyro: (f32, f32, f32),
-}
-
-pub async fn read_mpu6050(
-    State(mpu): State<Arc<Mutex<MPU6050>>>,
-) -> Result<Json<MPU6050Data>, String> {
-    let mut mpu = mpu.lock().await;
-    let accel = mpu.read_accel().map_err(|e| e.to_string())?;
-    let gyro = mpu.read_gyro().map_err(|e| e.to_string())?;
-    Ok(Json(MPU6050Data { accel, gyro }))
-}
\ No newline at end of file
+/// Reads whatever the Arduino has sent so far, without waiting for more
+pub async fn read_available() -> Result<Vec<u8>, SerialError> {
+    let mut port_guard: PortGuard<'_> = SERIAL_PORT.lock().await;
+
+    let port = match &mut *port_guard {
+        Some(p) => p,
+        None => return Err(SerialError::NotConnected),
+    };
+
+    let result = port.bytes_to_read().and_then(|available| {
+        let mut buffer = vec![0u8; available as usize];
+        if available > 0 {
+            let n = port.read(&mut buffer)?;
+            buffer.truncate(n);
+        }
+        Ok(buffer)
+    });
+
+    match result {
+        Ok(buffer) => Ok(buffer),
+        Err(e) => {
+            handle_io_error(port_guard, &e).await;
+            Err(SerialError::Read(e))
+        }
+    }
+}
+
+pub async fn send(Json(payload): Json<SerialMessage>) -> Response {
+    let Some(reply_timeout_ms) = payload.reply_timeout_ms else {
+        return match write_line(&payload.message).await {
+            Ok(Delivery::Written) => (StatusCode::OK, format!("Sent '{}'", payload.message)),
+            Ok(Delivery::Acknowledged) => (StatusCode::OK, format!("Sent '{}', acknowledged by the Arduino", payload.message)),
+            Err(e) => (e.status(), e.to_string()),
+        }
+        .into_response();
+    };
+
+    if reply_timeout_ms > MAX_REPLY_TIMEOUT_MS {
+        let error = CommandError::new(
+            "invalid_timeout",
+            format!("reply_timeout_ms must be at most {}, got {}", MAX_REPLY_TIMEOUT_MS, reply_timeout_ms),
+        );
+        return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+    }
+
+    // Subscribe before writing so a fast reply can't be missed
+    let mut lines = SERIAL_LINES.subscribe();
+    let delivery = match write_line(&payload.message).await {
+        Ok(delivery) => delivery,
+        Err(e) => return (e.status(), Json(CommandError::from(e))).into_response(),
+    };
+    let replies = collect_replies(&mut lines, Duration::from_millis(reply_timeout_ms)).await;
+
+    Json(SendResponse { sent: payload.message, delivery, replies }).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(request: serde_json::Value) -> Result<SerialSettings, String> {
+        serde_json::from_value::<SerialSettingsRequest>(request).unwrap().settings()
+    }
+
+    #[test]
+    fn missing_settings_default_to_8n1() {
+        let settings = settings(serde_json::json!({})).unwrap();
+        assert_eq!(settings.baud_rate, 115200);
+        assert_eq!(settings.data_bits, DataBits::Eight);
+        assert_eq!(settings.parity, Parity::None);
+        assert_eq!(settings.stop_bits, StopBits::One);
+        assert_eq!(settings.flow_control, FlowControl::None);
+        assert_eq!(settings.timeout, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn accepts_every_setting() {
+        let request = serde_json::json!({
+            "baud_rate": 9600,
+            "data_bits": 7,
+            "parity": "Even",
+            "stop_bits": 2,
+            "flow_control": "hardware",
+            "timeout_ms": 250,
+            "protocol": "framed",
+        });
+        let settings = settings(request).unwrap();
+        assert_eq!(settings.baud_rate, 9600);
+        assert_eq!(settings.data_bits, DataBits::Seven);
+        assert_eq!(settings.parity, Parity::Even);
+        assert_eq!(settings.stop_bits, StopBits::Two);
+        assert_eq!(settings.flow_control, FlowControl::Hardware);
+        assert_eq!(settings.timeout, Duration::from_millis(250));
+        assert_eq!(settings.protocol, Protocol::Framed);
+    }
+
+    #[test]
+    fn reported_settings_are_accepted_back() {
+        let original = settings(serde_json::json!({ "parity": "odd", "stop_bits": 2, "flow_control": "software" })).unwrap();
+        let reported = serde_json::to_value(original).unwrap();
+        let accepted = settings(reported.clone()).unwrap();
+        assert_eq!(serde_json::to_value(accepted).unwrap(), reported);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        for request in [
+            serde_json::json!({ "baud_rate": 0 }),
+            serde_json::json!({ "data_bits": 9 }),
+            serde_json::json!({ "data_bits": 4 }),
+            serde_json::json!({ "parity": "mark" }),
+            serde_json::json!({ "stop_bits": 0 }),
+            serde_json::json!({ "stop_bits": 3 }),
+            serde_json::json!({ "flow_control": "rts" }),
+            serde_json::json!({ "timeout_ms": 0 }),
+            serde_json::json!({ "protocol": "binary" }),
+        ] {
+            assert!(settings(request.clone()).is_err(), "{} was accepted", request);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::time::Duration;
+use axum::response::{Json, sse::{Event, Sse}};
+use futures_util::stream::Stream;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::{Mutex, broadcast};
+use tokio::time::Instant;
+
+use crate::battery;
+use crate::config::CONFIG;
+use crate::events::{now_ms, sse_from_broadcast};
+use crate::framing::{FRAME_REPLIES, decode_reply};
+use crate::serial::read_available;
+
+// How often the reader checks the serial port for new bytes
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Once a reply started, how long a pause ends it
+const REPLY_GAP: Duration = Duration::from_millis(50);
+
+// What the firmware answers to the empty line used as readiness ping
+const PING_REPLY: &str = "Unknown command:";
+
+pub static SERIAL_LINES: Lazy<SerialLines> = Lazy::new(SerialLines::new);
+
+/// A line printed by the Arduino
+#[derive(Serialize, Clone, Debug)]
+pub struct SerialLine {
+    /// Reception time, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub line: String,
+}
+
+pub struct SerialLines {
+    state: Mutex<LineState>,
+    sender: broadcast::Sender<SerialLine>,
+}
+
+struct LineState {
+    partial: String,
+    history: VecDeque<SerialLine>,
+}
+
+impl SerialLines {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Self {
+            state: Mutex::new(LineState { partial: String::new(), history: VecDeque::new() }),
+            sender,
+        }
+    }
+
+    /// Splits received bytes into lines, stores them and sends them to subscribers
+    pub async fn ingest(&self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.lock().await;
+        state.partial.push_str(&String::from_utf8_lossy(bytes));
+        let mut voltages = Vec::new();
+
+        while let Some(end) = state.partial.find('\n') {
+            let line: String = state.partial.drain(..=end).collect();
+            let line = line.trim_end();
+            if line.is_empty() || line == PING_REPLY {
+                continue;
+            }
+            // Acks of framed commands go to the writer waiting for them
+            if let Some(reply) = decode_reply(line) {
+                let _ = FRAME_REPLIES.send(reply);
+                continue;
+            }
+            // Periodic telemetry would drown the replies to commands
+            if let Some(voltage) = battery::parse_telemetry(line) {
+                voltages.push(voltage);
+                continue;
+            }
+
+            let line = SerialLine { timestamp_ms: now_ms(), line: line.to_string() };
+            if state.history.len() >= CONFIG.serial_history_lines {
+                state.history.pop_front();
+            }
+            state.history.push_back(line.clone());
+            // No subscribers is fine, the line is still kept in the history
+            let _ = self.sender.send(line);
+        }
+        drop(state);
+
+        for voltage in voltages {
+            battery::record(voltage).await;
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SerialLine> {
+        self.sender.subscribe()
+    }
+
+    pub async fn history(&self) -> Vec<SerialLine> {
+        self.state.lock().await.history.iter().cloned().collect()
+    }
+
+    /// Drops a partially received line, e.g. after reconnecting
+    pub async fn reset(&self) {
+        self.state.lock().await.partial.clear();
+    }
+}
+
+/// Collects the lines received within `timeout`, returning early once they stop coming
+pub async fn collect_replies(receiver: &mut broadcast::Receiver<SerialLine>, timeout: Duration) -> Vec<SerialLine> {
+    let deadline = Instant::now() + timeout;
+    let mut replies = Vec::new();
+
+    loop {
+        let wait_until = if replies.is_empty() { deadline } else { (Instant::now() + REPLY_GAP).min(deadline) };
+        match tokio::time::timeout_at(wait_until, receiver.recv()).await {
+            Ok(Ok(line)) => replies.push(line),
+            Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => break,
+        }
+    }
+    replies
+}
+
+/// Starts the task reading everything the Arduino prints
+pub fn spawn() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            // Not being connected is expected, just wait for a connection
+            if let Ok(bytes) = read_available().await {
+                SERIAL_LINES.ingest(&bytes).await;
+            }
+        }
+    });
+}
+
+pub async fn serial_log() -> Json<Vec<SerialLine>> {
+    Json(SERIAL_LINES.history().await)
+}
+
+pub async fn serial_events() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    sse_from_broadcast(SERIAL_LINES.subscribe())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battery::battery_status;
+    use crate::framing::FrameReply;
+
+    async fn lines(serial_lines: &SerialLines) -> Vec<String> {
+        serial_lines.history().await.into_iter().map(|l| l.line).collect()
+    }
+
+    #[tokio::test]
+    async fn buffers_partial_lines_across_reads() {
+        let serial_lines = SerialLines::new();
+        serial_lines.ingest(b"Speed se").await;
+        assert!(lines(&serial_lines).await.is_empty());
+        serial_lines.ingest(b"t to: 800\r\nInv").await;
+        assert_eq!(lines(&serial_lines).await, ["Speed set to: 800"]);
+        serial_lines.ingest(b"alid wheels command\n").await;
+        assert_eq!(lines(&serial_lines).await, ["Speed set to: 800", "Invalid wheels command"]);
+    }
+
+    #[tokio::test]
+    async fn strips_line_endings_and_skips_blank_lines() {
+        let serial_lines = SerialLines::new();
+        serial_lines.ingest(b"one\r\n\r\n\ntwo  \nthree\r\n").await;
+        assert_eq!(lines(&serial_lines).await, ["one", "two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn drops_the_reply_to_the_readiness_ping() {
+        let serial_lines = SerialLines::new();
+        serial_lines.ingest(b"Unknown command: \r\nUnknown command: fly\r\n").await;
+        assert_eq!(lines(&serial_lines).await, ["Unknown command: fly"]);
+    }
+
+    #[tokio::test]
+    async fn sends_frame_replies_to_the_writer() {
+        let serial_lines = SerialLines::new();
+        let mut frame_replies = FRAME_REPLIES.subscribe();
+        let ack = FrameReply::Ack { seq: 60_001 };
+        let nack = FrameReply::Nack { seq: 60_002, reason: "crc".to_string() };
+        serial_lines.ingest(format!("{}\r\n{}\r\n", ack, nack).as_bytes()).await;
+
+        assert!(lines(&serial_lines).await.is_empty());
+        // Other tests may send replies too
+        let mut received = Vec::new();
+        while let Ok(reply) = frame_replies.try_recv() {
+            if reply.seq() > 60_000 {
+                received.push(reply);
+            }
+        }
+        assert_eq!(received, [ack, nack]);
+    }
+
+    #[tokio::test]
+    async fn records_battery_telemetry() {
+        let serial_lines = SerialLines::new();
+        serial_lines.ingest(b"battery 11.37\r\n").await;
+        assert!(lines(&serial_lines).await.is_empty());
+        let status = serde_json::to_value(battery_status().await.0).unwrap();
+        let history = status["history"].as_array().unwrap();
+        assert!(history.iter().any(|reading| reading["voltage"].as_f64().unwrap() as f32 == 11.37));
+    }
+
+    #[tokio::test]
+    async fn sends_lines_to_subscribers() {
+        let serial_lines = SerialLines::new();
+        let mut receiver = serial_lines.subscribe();
+        serial_lines.ingest(b"firmware carbot 1.1.0\r\n").await;
+        assert_eq!(receiver.recv().await.unwrap().line, "firmware carbot 1.1.0");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn collects_replies_until_they_stop() {
+        let (sender, mut receiver) = broadcast::channel(8);
+        let line = |line: &str| SerialLine { timestamp_ms: 0, line: line.to_string() };
+        sender.send(line("Speed set to: 800")).unwrap();
+        sender.send(line("firmware carbot 1.1.0")).unwrap();
+
+        let start = Instant::now();
+        let replies = collect_replies(&mut receiver, Duration::from_secs(1)).await;
+        assert_eq!(replies.iter().map(|l| l.line.as_str()).collect::<Vec<_>>(), ["Speed set to: 800", "firmware carbot 1.1.0"]);
+        assert_eq!(start.elapsed(), REPLY_GAP);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_out_the_timeout_without_replies() {
+        let (_sender, mut receiver) = broadcast::channel::<SerialLine>(8);
+        let start = Instant::now();
+        assert!(collect_replies(&mut receiver, Duration::from_millis(300)).await.is_empty());
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::camera::{encode_color_frame, encode_depth_frame};
+use crate::config::CONFIG;
+use crate::hardware::{CameraSource, Imu, ImuSettings, MotorLink};
+use crate::virtual_arduino::{TELEMETRY_INTERVAL, VIRTUAL_ARDUINO};
+
+// Color bars of the test pattern, in BGR order
+const COLOR_BARS: [[u8; 3]; 8] = [
+    [255, 255, 255], // white
+    [0, 255, 255],   // yellow
+    [255, 255, 0],   // cyan
+    [0, 255, 0],     // green
+    [255, 0, 255],   // magenta
+    [0, 0, 255],     // red
+    [255, 0, 0],     // blue
+    [0, 0, 0],       // black
+];
+
+// Depth range covered by the synthetic ramp, in millimeters
+const DEPTH_NEAR_MM: f32 = 200.0;
+const DEPTH_FAR_MM: f32 = 5000.0;
+
+/// Camera stand-in producing a moving test pattern and a synthetic depth ramp
+pub struct SimulatedCamera {
+    width: u32,
+    height: u32,
+    frame_interval: Duration,
+    frame_index: u32,
+    next_frame_at: Instant,
+}
+
+impl SimulatedCamera {
+    pub fn new() -> Self {
+        Self {
+            width: CONFIG.camera_width,
+            height: CONFIG.camera_height,
+            frame_interval: Duration::from_secs(1) / CONFIG.camera_fps.max(1),
+            frame_index: 0,
+            next_frame_at: Instant::now(),
+        }
+    }
+
+    // Color bars scrolling to the left with a white square bouncing over them
+    fn color_frame(&self) -> Vec<u8> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let bar_width = (width / COLOR_BARS.len()).max(1);
+        let offset = self.frame_index as usize * 4;
+
+        let square = (height / 4).max(1);
+        let square_x = bounce(self.frame_index as usize * 6, width.saturating_sub(square));
+        let square_y = bounce(self.frame_index as usize * 3, height.saturating_sub(square));
+
+        let mut bgr = vec![0u8; width * height * 3];
+        for (i, pixel) in bgr.chunks_exact_mut(3).enumerate() {
+            let (x, y) = (i % width, i / width);
+            let in_square = (square_x..square_x + square).contains(&x)
+                && (square_y..square_y + square).contains(&y);
+            let color = if in_square {
+                [255, 255, 255]
+            } else {
+                COLOR_BARS[((x + offset) / bar_width) % COLOR_BARS.len()]
+            };
+            pixel.copy_from_slice(&color);
+        }
+        bgr
+    }
+
+    // Depth increasing from the bottom to the top of the image, slowly moving
+    // back and forth, with a hole of missing data in the corner
+    fn depth_frame(&self) -> Vec<u16> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let shift = bounce(self.frame_index as usize, height) as f32 / height.max(1) as f32;
+
+        let mut depth = vec![0u16; width * height];
+        for (i, value) in depth.iter_mut().enumerate() {
+            let (x, y) = (i % width, i / width);
+            if x < width / 8 && y < height / 8 {
+                continue; // No data
+            }
+            let t = (1.0 - y as f32 / height as f32 + shift) % 1.0;
+            *value = (DEPTH_NEAR_MM + t * (DEPTH_FAR_MM - DEPTH_NEAR_MM)) as u16;
+        }
+        depth
+    }
+}
+
+// Position moving back and forth between 0 and max as step increases
+fn bounce(step: usize, max: usize) -> usize {
+    if max == 0 {
+        return 0;
+    }
+    let phase = step % (2 * max);
+    if phase < max { phase } else { 2 * max - phase }
+}
+
+impl CameraSource for SimulatedCamera {
+    fn next_frames(&mut self) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let now = Instant::now();
+        if self.next_frame_at > now {
+            std::thread::sleep(self.next_frame_at - now);
+        }
+        self.next_frame_at += self.frame_interval;
+
+        let color = encode_color_frame(self.width, self.height, &self.color_frame());
+        let depth = encode_depth_frame(self.width, self.height, 0.001, &self.depth_frame());
+        self.frame_index = self.frame_index.wrapping_add(1);
+        Ok((color, depth))
+    }
+}
+
+/// IMU stand-in for a robot lying flat and standing still
+pub struct SimulatedImu;
+
+impl SimulatedImu {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Imu for SimulatedImu {
+    fn read_accel(&mut self) -> Result<(f32, f32, f32), Box<dyn std::error::Error>> {
+        Ok((0.0, 0.0, 1.0))
+    }
+
+    fn read_gyro(&mut self) -> Result<(f32, f32, f32), Box<dyn std::error::Error>> {
+        Ok((0.0, 0.0, 0.0))
+    }
+
+    fn configure(&mut self, _settings: &ImuSettings) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+/// Motor link stand-in talking to the in-process virtual Arduino
+pub struct SimulatedMotorLink {
+    // Locked so `bytes_to_read` can pick up the telemetry printed in the meantime
+    output: Mutex<LinkOutput>,
+}
+
+struct LinkOutput {
+    replies: VecDeque<u8>,
+    last_telemetry: Instant,
+}
+
+impl SimulatedMotorLink {
+    pub fn new() -> Self {
+        Self { output: Mutex::new(LinkOutput { replies: VecDeque::new(), last_telemetry: Instant::now() }) }
+    }
+
+    fn output(&self) -> MutexGuard<'_, LinkOutput> {
+        let mut output = self.output.lock().unwrap();
+        if output.last_telemetry.elapsed() >= TELEMETRY_INTERVAL {
+            output.last_telemetry = Instant::now();
+            let telemetry = VIRTUAL_ARDUINO.lock().unwrap().telemetry();
+            output.replies.extend(telemetry);
+        }
+        output
+    }
+}
+
+impl Write for SimulatedMotorLink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let reply = VIRTUAL_ARDUINO.lock().unwrap().receive(buf);
+        self.output().replies.extend(reply);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for SimulatedMotorLink {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut output = self.output();
+        let replies = &mut output.replies;
+        if replies.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Operation timed out"));
+        }
+        let n = buf.len().min(replies.len());
+        for (dst, src) in buf.iter_mut().zip(replies.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl MotorLink for SimulatedMotorLink {
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        Ok(self.output().replies.len() as u32)
+    }
+}
@@ -0,0 +1,18 @@
+// Helpers shared by the unit tests
+
+use crate::imu::ImuSample;
+
+pub fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+    assert!((actual - expected).abs() <= tolerance, "{} is not within {} of {}", actual, tolerance, expected);
+}
+
+pub fn assert_all_close(actual: [f32; 3], expected: [f32; 3], tolerance: f32) {
+    assert!(
+        actual.iter().zip(expected).all(|(a, e)| (a - e).abs() <= tolerance),
+        "{:?} is not within {} of {:?}", actual, tolerance, expected
+    );
+}
+
+pub fn imu_sample(t_us: u64, accel: (f32, f32, f32), gyro: (f32, f32, f32)) -> ImuSample {
+    ImuSample { t_us, timestamp_ms: 0, accel, gyro }
+}
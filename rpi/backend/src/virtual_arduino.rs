@@ -0,0 +1,369 @@
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use axum::response::Json;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Serialize;
+use serialport::{SerialPort, TTYPort};
+
+use crate::framing::{FrameReply, crc8};
+use crate::mecanum::WheelSpeeds;
+
+// Emulated firmware shared by the simulated motor link and the pseudo-terminal
+pub static VIRTUAL_ARDUINO: Lazy<Arc<Mutex<VirtualArduino>>> = Lazy::new(|| {
+    Arc::new(Mutex::new(VirtualArduino::new()))
+});
+
+// Reply of the firmware to `info`
+const FIRMWARE_INFO: &str = "firmware carbot 1.1.0\r\n\
+    commands forward backward left right rotate_left rotate_right stop speed wheels info\r\n\
+    features framed battery\r\n";
+
+// How often the firmware prints the battery voltage
+pub const TELEMETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Emulated 3S pack: full charge, discharge rates in volts per second, and sag while driving
+const BATTERY_FULL: f32 = 12.6;
+const BATTERY_DRAIN_IDLE: f32 = 0.0002;
+const BATTERY_DRAIN_MOVING: f32 = 0.002;
+const BATTERY_SAG_MOVING: f32 = 0.3;
+
+// Path of the pseudo-terminal, once started
+pub static VIRTUAL_ARDUINO_PORT: OnceCell<String> = OnceCell::new();
+
+/// Emulates the command handling of `arduino/carbot/carbot.ino`
+pub struct VirtualArduino {
+    command: String,
+    wheel_speed: i32,
+    wheels: WheelSpeeds,
+    // Sequence number and CRC of the last framed command, to recognise retries
+    last_frame: Option<(u16, u8)>,
+    // Open-circuit voltage of the emulated battery
+    battery: f32,
+    last_discharge: Instant,
+}
+
+impl VirtualArduino {
+    pub fn new() -> Self {
+        Self {
+            command: String::new(),
+            wheel_speed: 1000,
+            wheels: WheelSpeeds::default(),
+            last_frame: None,
+            battery: BATTERY_FULL,
+            last_discharge: Instant::now(),
+        }
+    }
+
+    /// Speed used by the motion commands, as set by `speed N`
+    pub fn wheel_speed(&self) -> i32 {
+        self.wheel_speed
+    }
+
+    /// Speeds of the four wheels after the last motion command
+    pub fn wheels(&self) -> WheelSpeeds {
+        self.wheels
+    }
+
+    /// Battery voltage as measured on A0, lower while the wheels turn
+    pub fn battery_voltage(&self) -> f32 {
+        if self.wheels == WheelSpeeds::default() { self.battery } else { self.battery - BATTERY_SAG_MOVING }
+    }
+
+    /// Battery telemetry line, printed by the main loop every `TELEMETRY_INTERVAL`
+    pub fn telemetry(&mut self) -> Vec<u8> {
+        self.discharge();
+        format!("battery {:.2}\r\n", self.battery_voltage()).into_bytes()
+    }
+
+    fn discharge(&mut self) {
+        let drain = if self.wheels == WheelSpeeds::default() { BATTERY_DRAIN_IDLE } else { BATTERY_DRAIN_MOVING };
+        self.battery -= drain * self.last_discharge.elapsed().as_secs_f32();
+        self.last_discharge = Instant::now();
+    }
+
+    /// Feeds bytes received over serial, returning whatever the firmware would print back
+    pub fn receive(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for &c in bytes {
+            if c == b'\n' {
+                let command = std::mem::take(&mut self.command);
+                output.extend(self.handle_line(&command).bytes());
+            } else {
+                self.command.push(c as char);
+            }
+        }
+        output
+    }
+
+    fn handle_line(&mut self, line: &str) -> String {
+        let line = line.trim();
+        if line.starts_with('#') {
+            return self.handle_frame(line);
+        }
+        let mut output = String::new();
+        let _ = self.execute_command(line, &mut output);
+        output
+    }
+
+    // Framed command "#<seq> <command>*<crc>", answered with an ack or a nack
+    fn handle_frame(&mut self, frame: &str) -> String {
+        let (Some(star), Some(space)) = (frame.rfind('*'), frame.find(' ')) else {
+            return String::new(); // Not even a sequence number to answer to
+        };
+        if space > star {
+            return String::new();
+        }
+        let body = &frame[1..star];
+        let seq = to_int(&body[..space - 1]) as u16;
+        let crc = u8::from_str_radix(frame[star + 1..].trim(), 16).unwrap_or(0);
+
+        let mut output = String::new();
+        let reply = if crc != crc8(body.as_bytes()) {
+            FrameReply::Nack { seq, reason: "crc".to_string() }
+        } else if self.last_frame == Some((seq, crc)) {
+            // Retry of a command whose ack got lost, don't run it twice
+            FrameReply::Ack { seq }
+        } else {
+            self.last_frame = Some((seq, crc));
+            match self.execute_command(body[space..].trim(), &mut output) {
+                Ok(()) => FrameReply::Ack { seq },
+                Err(reason) => FrameReply::Nack { seq, reason: reason.to_string() },
+            }
+        };
+        output.push_str(&format!("{}\r\n", reply));
+        output
+    }
+
+    /// Runs a command, printing to `output`; the error is the nack reason
+    fn execute_command(&mut self, cmd: &str, output: &mut String) -> Result<(), &'static str> {
+        let cmd = cmd.trim(); // Remove extra spaces/newlines
+        let s = self.wheel_speed;
+
+        match cmd {
+            "forward" => self.set_all_motors(s, s, s, s),
+            "backward" => self.set_all_motors(-s, -s, -s, -s),
+            "left" => self.set_all_motors(-s, s, s, -s),
+            "right" => self.set_all_motors(s, -s, -s, s),
+            "rotate_left" => self.set_all_motors(-s, -s, s, s),
+            "rotate_right" => self.set_all_motors(s, s, -s, -s),
+            "stop" => self.set_all_motors(0, 0, 0, 0),
+            "info" => output.push_str(FIRMWARE_INFO),
+            _ if cmd.starts_with("wheels") => {
+                let speeds: Vec<i32> = cmd[6..].split_whitespace().filter_map(|s| s.parse().ok()).collect();
+                match speeds[..] {
+                    [lf, lb, rf, rb] => self.set_all_motors(
+                        lf.clamp(-3000, 3000),
+                        lb.clamp(-3000, 3000),
+                        rf.clamp(-3000, 3000),
+                        rb.clamp(-3000, 3000),
+                    ),
+                    _ => {
+                        output.push_str(&format!("Invalid wheels command: {}\r\n", cmd));
+                        return Err("invalid");
+                    }
+                }
+            }
+            _ if cmd.starts_with("speed") => {
+                self.wheel_speed = to_int(cmd.get(6..).unwrap_or("")).clamp(100, 3000);
+                output.push_str(&format!("Speed set to: {}\r\n", self.wheel_speed));
+            }
+            _ => {
+                output.push_str(&format!("Unknown command: {}\r\n", cmd));
+                return Err("unknown");
+            }
+        }
+        Ok(())
+    }
+
+    fn set_all_motors(&mut self, lf: i32, lb: i32, rf: i32, rb: i32) {
+        // Drain at the rate of the previous motion up to now
+        self.discharge();
+        self.wheels = WheelSpeeds { lf, lb, rf, rb };
+    }
+}
+
+// Arduino's String::toInt: optional sign and leading digits, 0 if there are none
+fn to_int(s: &str) -> i32 {
+    let s = s.trim_start();
+    let (sign, digits) = match s.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let value = digits
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .fold(0i64, |acc, c| (acc * 10 + c.to_digit(10).unwrap() as i64).min(i32::MAX as i64));
+    (sign * value) as i32
+}
+
+/// Starts the virtual Arduino on a new pseudo-terminal, which can be opened like `/dev/ttyUSB0`
+pub fn spawn_pty() -> Result<String, serialport::Error> {
+    let (mut master, slave) = TTYPort::pair()?;
+    let port_path = slave.name().unwrap_or_default();
+    master.set_timeout(Duration::from_millis(100))?;
+
+    std::thread::spawn(move || {
+        // Keep the slave side open so the master doesn't fail while no client is connected
+        let _slave = slave;
+        let mut buffer = [0u8; 64];
+        let mut last_telemetry = Instant::now();
+
+        loop {
+            if last_telemetry.elapsed() >= TELEMETRY_INTERVAL {
+                last_telemetry = Instant::now();
+                let telemetry = VIRTUAL_ARDUINO.lock().unwrap().telemetry();
+                if master.write_all(&telemetry).is_err() {
+                    eprintln!("Virtual Arduino: failed to write telemetry");
+                }
+            }
+
+            match master.read(&mut buffer) {
+                Ok(n) if n > 0 => {
+                    let reply = VIRTUAL_ARDUINO.lock().unwrap().receive(&buffer[..n]);
+                    if !reply.is_empty() && master.write_all(&reply).is_err() {
+                        eprintln!("Virtual Arduino: failed to write reply");
+                    }
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+                Err(e) => {
+                    eprintln!("Virtual Arduino stopped: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    let _ = VIRTUAL_ARDUINO_PORT.set(port_path.clone());
+    Ok(port_path)
+}
+
+#[derive(Serialize)]
+pub struct VirtualArduinoState {
+    port_path: Option<String>,
+    wheel_speed: i32,
+    wheels: WheelSpeeds,
+    battery_voltage: f32,
+}
+
+pub async fn virtual_arduino_state() -> Json<VirtualArduinoState> {
+    let arduino = VIRTUAL_ARDUINO.lock().unwrap();
+    Json(VirtualArduinoState {
+        port_path: VIRTUAL_ARDUINO_PORT.get().cloned(),
+        wheel_speed: arduino.wheel_speed(),
+        wheels: arduino.wheels(),
+        battery_voltage: arduino.battery_voltage(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::{decode_reply, encode_command};
+    use crate::hardware::MotorLink;
+    use crate::simulation::SimulatedMotorLink;
+
+    fn run(arduino: &mut VirtualArduino, cmd: &str) -> (Result<(), &'static str>, String) {
+        let mut output = String::new();
+        let result = arduino.execute_command(cmd, &mut output);
+        (result, output)
+    }
+
+    #[test]
+    fn to_int_reads_leading_digits_like_the_arduino() {
+        assert_eq!(to_int("800"), 800);
+        assert_eq!(to_int("  -250"), -250);
+        assert_eq!(to_int("+42"), 42);
+        assert_eq!(to_int("12abc"), 12);
+        assert_eq!(to_int("abc"), 0);
+        assert_eq!(to_int(""), 0);
+        assert_eq!(to_int("99999999999"), i32::MAX);
+    }
+
+    #[test]
+    fn executes_the_firmware_commands() {
+        let mut arduino = VirtualArduino::new();
+        assert_eq!(run(&mut arduino, "speed 800"), (Ok(()), "Speed set to: 800\r\n".to_string()));
+        assert_eq!(run(&mut arduino, " forward "), (Ok(()), String::new()));
+        assert_eq!(arduino.wheels(), WheelSpeeds { lf: 800, lb: 800, rf: 800, rb: 800 });
+        assert_eq!(run(&mut arduino, "rotate_left").0, Ok(()));
+        assert_eq!(arduino.wheels(), WheelSpeeds { lf: -800, lb: -800, rf: 800, rb: 800 });
+
+        // Out of range speeds are clamped, like `constrain` in the firmware
+        assert_eq!(run(&mut arduino, "speed 5").1, "Speed set to: 100\r\n");
+        assert_eq!(run(&mut arduino, "wheels 100 -200 4000 0").0, Ok(()));
+        assert_eq!(arduino.wheels(), WheelSpeeds { lf: 100, lb: -200, rf: 3000, rb: 0 });
+        assert_eq!(run(&mut arduino, "wheels 1 2 3"), (Err("invalid"), "Invalid wheels command: wheels 1 2 3\r\n".to_string()));
+
+        let (result, output) = run(&mut arduino, "info");
+        assert_eq!(result, Ok(()));
+        assert!(output.starts_with("firmware carbot "));
+        assert!(output.contains("features framed battery"));
+    }
+
+    #[test]
+    fn unknown_commands_are_reported() {
+        // The backend pings with a command the firmware doesn't know
+        assert_eq!(run(&mut VirtualArduino::new(), "ping"), (Err("unknown"), "Unknown command: ping\r\n".to_string()));
+    }
+
+    #[test]
+    fn framed_commands_are_acknowledged() {
+        let mut arduino = VirtualArduino::new();
+        assert_eq!(arduino.handle_line(&encode_command(7, "speed 900")), "Speed set to: 900\r\n#7 ACK*CE\r\n");
+        assert_eq!(decode_reply(arduino.handle_line(&encode_command(8, "fly")).lines().last().unwrap()), Some(FrameReply::Nack { seq: 8, reason: "unknown".to_string() }));
+    }
+
+    #[test]
+    fn corrupted_frames_are_rejected() {
+        let mut arduino = VirtualArduino::new();
+        assert_eq!(arduino.handle_line("#7 forward*00"), "#7 NACK crc*7D\r\n");
+        assert_eq!(arduino.wheels(), WheelSpeeds::default());
+        // Nothing to answer to without a sequence number
+        assert_eq!(arduino.handle_line("#forward"), "");
+    }
+
+    #[test]
+    fn retried_frames_run_once() {
+        let mut arduino = VirtualArduino::new();
+        let frame = encode_command(3, "speed 500");
+        let ack = format!("{}\r\n", FrameReply::Ack { seq: 3 });
+        assert_eq!(arduino.handle_line(&frame), format!("Speed set to: 500\r\n{}", ack));
+        arduino.execute_command("speed 700", &mut String::new()).unwrap();
+        // Acknowledged again without setting the speed back to 500
+        assert_eq!(arduino.handle_line(&frame), ack);
+        assert_eq!(arduino.wheel_speed(), 700);
+    }
+
+    #[test]
+    fn receive_buffers_partial_lines() {
+        let mut arduino = VirtualArduino::new();
+        assert!(arduino.receive(b"spe").is_empty());
+        assert!(arduino.receive(b"ed 6").is_empty());
+        assert_eq!(arduino.receive(b"00\r\nforw"), b"Speed set to: 600\r\n");
+        assert!(arduino.receive(b"ard\nstop\nspeed").is_empty());
+        assert_eq!(arduino.wheels(), WheelSpeeds::default());
+        assert_eq!(arduino.receive(b" 300\n"), b"Speed set to: 300\r\n");
+    }
+
+    #[test]
+    fn simulated_link_round_trip() {
+        let mut link = SimulatedMotorLink::new();
+        link.write_all(format!("{}\n", encode_command(11, "speed 1200")).as_bytes()).unwrap();
+
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 8];
+        while link.bytes_to_read().unwrap() > 0 {
+            let n = link.read(&mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..n]);
+        }
+        let received = String::from_utf8(received).unwrap();
+        // Telemetry may come in between
+        let replies: Vec<&str> = received.lines().filter(|line| !line.starts_with("battery")).collect();
+        assert_eq!(replies[0], "Speed set to: 1200");
+        assert_eq!(decode_reply(replies[1]), Some(FrameReply::Ack { seq: 11 }));
+        assert_eq!(replies.len(), 2);
+        assert!(link.read(&mut buffer).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::time::Duration;
+use axum::http::StatusCode;
+use axum::response::Json;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::command::MotionCommand;
+use crate::config::CONFIG;
+use crate::serial::write_line;
+
+// How often the watchdog checks for a silent operator
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+pub static WATCHDOG: Lazy<Mutex<Watchdog>> = Lazy::new(|| {
+    Mutex::new(Watchdog {
+        enabled: CONFIG.watchdog_enabled,
+        timeout: Duration::from_millis(CONFIG.watchdog_timeout_ms),
+        last_activity: None,
+        moving: false,
+        trips: 0,
+    })
+});
+
+/// Stops the robot when no command or heartbeat arrives for `timeout` while it is moving
+pub struct Watchdog {
+    enabled: bool,
+    timeout: Duration,
+    last_activity: Option<Instant>,
+    moving: bool,
+    trips: u32,
+}
+
+impl Watchdog {
+    fn expired(&self) -> bool {
+        self.enabled
+            && self.moving
+            && self.last_activity.is_some_and(|t| t.elapsed() >= self.timeout)
+    }
+
+    fn feed(&mut self) {
+        self.last_activity = Some(Instant::now());
+    }
+
+    fn record_command(&mut self, line: &str) {
+        self.feed();
+        if let Some(moving) = line.parse::<MotionCommand>().ok().and_then(|c| c.is_moving()) {
+            self.moving = moving;
+        }
+    }
+
+    // Whether the robot has to be stopped, counting it as stopped from now on
+    fn trip(&mut self) -> bool {
+        if !self.expired() {
+            return false;
+        }
+        self.moving = false;
+        self.trips += 1;
+        true
+    }
+}
+
+/// Records a line written to the Arduino as operator activity
+pub async fn record_command(line: &str) {
+    WATCHDOG.lock().await.record_command(line);
+}
+
+/// Marks the robot as stopped, e.g. when the serial port is closed
+pub async fn disarm() {
+    WATCHDOG.lock().await.moving = false;
+}
+
+pub fn spawn() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            {
+                let mut watchdog = WATCHDOG.lock().await;
+                if !watchdog.trip() {
+                    continue;
+                }
+                eprintln!("Watchdog: no command or heartbeat for {:?}, stopping", watchdog.timeout);
+            }
+
+            if let Err(e) = write_line("stop").await {
+                eprintln!("Watchdog: failed to send stop: {}", e);
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+pub struct WatchdogStatus {
+    enabled: bool,
+    timeout_ms: u64,
+    moving: bool,
+    ms_since_activity: Option<u64>,
+    trips: u32,
+}
+
+pub async fn watchdog_status() -> Json<WatchdogStatus> {
+    let watchdog = WATCHDOG.lock().await;
+    Json(WatchdogStatus {
+        enabled: watchdog.enabled,
+        timeout_ms: watchdog.timeout.as_millis() as u64,
+        moving: watchdog.moving,
+        ms_since_activity: watchdog.last_activity.map(|t| t.elapsed().as_millis() as u64),
+        trips: watchdog.trips,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct WatchdogSettings {
+    enabled: Option<bool>,
+    timeout_ms: Option<u64>,
+}
+
+pub async fn configure_watchdog(Json(payload): Json<WatchdogSettings>) -> Result<Json<WatchdogStatus>, (StatusCode, String)> {
+    {
+        let mut watchdog = WATCHDOG.lock().await;
+        if let Some(timeout_ms) = payload.timeout_ms {
+            if timeout_ms == 0 {
+                return Err((StatusCode::BAD_REQUEST, "timeout_ms must be greater than 0".to_string()));
+            }
+            watchdog.timeout = Duration::from_millis(timeout_ms);
+        }
+        if let Some(enabled) = payload.enabled {
+            watchdog.enabled = enabled;
+        }
+    }
+    Ok(watchdog_status().await)
+}
+
+/// Records a heartbeat from the operator
+pub async fn feed() {
+    WATCHDOG.lock().await.feed();
+}
+
+pub async fn heartbeat() -> StatusCode {
+    feed().await;
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    fn watchdog() -> Watchdog {
+        Watchdog { enabled: true, timeout: TIMEOUT, last_activity: None, moving: false, trips: 0 }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn trips_once_after_the_timeout_while_moving() {
+        let mut watchdog = watchdog();
+        watchdog.record_command("forward");
+        assert!(watchdog.moving);
+
+        advance(TIMEOUT - Duration::from_millis(1)).await;
+        assert!(!watchdog.trip());
+        advance(Duration::from_millis(1)).await;
+        assert!(watchdog.trip());
+        assert_eq!(watchdog.trips, 1);
+        assert!(!watchdog.moving);
+
+        // Already stopped
+        advance(TIMEOUT).await;
+        assert!(!watchdog.trip());
+        assert_eq!(watchdog.trips, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_motions_arm_it() {
+        let mut watchdog = watchdog();
+        watchdog.record_command("speed 800");
+        assert!(!watchdog.moving);
+        watchdog.record_command("wheels 0 0 0 0");
+        assert!(!watchdog.moving);
+        watchdog.record_command("wheels 0 100 0 0");
+        assert!(watchdog.moving);
+        // Neither arms nor disarms it
+        watchdog.record_command("speed 1000");
+        assert!(watchdog.moving);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stop_disarms_it() {
+        let mut watchdog = watchdog();
+        watchdog.record_command("rotate_left");
+        watchdog.record_command("stop");
+        assert!(!watchdog.moving);
+        advance(TIMEOUT * 2).await;
+        assert!(!watchdog.trip());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_keep_it_from_tripping() {
+        let mut watchdog = watchdog();
+        watchdog.record_command("forward");
+        for _ in 0..10 {
+            advance(TIMEOUT / 2).await;
+            watchdog.feed();
+            assert!(!watchdog.trip());
+        }
+        advance(TIMEOUT).await;
+        assert!(watchdog.trip());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disabled_never_trips() {
+        let mut watchdog = Watchdog { enabled: false, ..watchdog() };
+        watchdog.record_command("forward");
+        advance(TIMEOUT * 2).await;
+        assert!(!watchdog.trip());
+    }
+}
@@ -1,11 +1,21 @@
 use std::{sync::Arc, time::Duration};
 
 use axum::{
-    extract::ws::{WebSocketUpgrade, Message},
+    extract::{Query, ws::{WebSocketUpgrade, Message}},
+    http::StatusCode,
+    response::{IntoResponse, Response},
 };
-use tokio::{sync::Mutex, time::sleep};
+use tokio::{sync::{Mutex, broadcast}, time::sleep};
 use once_cell::sync::Lazy;
 use futures_util::{sink::SinkExt, stream::StreamExt};
+use serde::{Serialize, Deserialize};
+
+use crate::command::{CommandError, MotionCommand};
+use crate::imu::{IMU_STREAM, ImuSample};
+use crate::mecanum::VelocityRequest;
+use crate::serial::{Delivery, write_line, write_line_unchecked};
+use crate::serial_reader::SERIAL_LINES;
+use crate::watchdog;
 
 pub static LAST_FRAME: Lazy<Arc<Mutex<Option<Vec<u8>>>>> = Lazy::new(|| {
     Arc::new(Mutex::new(None))
@@ -19,14 +29,297 @@ pub async fn websocket_handler(ws: WebSocketUpgrade) -> impl axum::response::Int
         loop {
             {
                 let frame_guard = last_frame.lock().await;
-                if let Some(frame) = &*frame_guard {
-                    if sender.send(Message::Binary(frame.clone())).await.is_err() {
-                        eprintln!("Error when sending frame: connection closed");
-                        break;
-                    }
+                if let Some(frame) = &*frame_guard
+                    && sender.send(Message::Binary(frame.clone())).await.is_err()
+                {
+                    eprintln!("Error when sending frame: connection closed");
+                    break;
                 }
             }
             sleep(Duration::from_millis(33)).await; // ~30 FPS
         }
     })
-}
\ No newline at end of file
+}
+
+// Requests may also carry a numeric "seq", echoed back to match the acknowledgement
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ControlMessage {
+    Command(MotionCommand),
+    Velocity(VelocityRequest),
+    Heartbeat { heartbeat: bool },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ControlEvent {
+    Ack { seq: Option<u64>, sent: String, delivery: Delivery },
+    Error {
+        seq: Option<u64>,
+        #[serde(flatten)]
+        error: CommandError,
+    },
+    Reply { line: String },
+}
+
+pub async fn control_websocket_handler(ws: WebSocketUpgrade) -> impl axum::response::IntoResponse {
+    ws.on_upgrade(|socket| async move {
+        let (mut sender, mut receiver) = socket.split();
+        let mut replies = SERIAL_LINES.subscribe();
+        // Whether the Arduino answered the readiness check, so later commands can skip it
+        let mut ready = false;
+
+        loop {
+            let event = tokio::select! {
+                message = receiver.next() => match message {
+                    Some(Ok(Message::Text(text))) => match handle_control_message(&text, &mut ready).await {
+                        Some(event) => event,
+                        None => continue,
+                    },
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                },
+                reply = replies.recv() => match reply {
+                    Ok(reply) => ControlEvent::Reply { line: reply.line },
+                    Err(_) => continue,
+                },
+            };
+
+            let json = serde_json::to_string(&event).unwrap();
+            if sender.send(Message::Text(json)).await.is_err() {
+                eprintln!("Error when sending control event: connection closed");
+                break;
+            }
+        }
+    })
+}
+
+async fn handle_control_message(text: &str, ready: &mut bool) -> Option<ControlEvent> {
+    let value: serde_json::Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(e) => {
+            let error = CommandError::new("invalid_message", e.to_string());
+            return Some(ControlEvent::Error { seq: None, error });
+        }
+    };
+
+    let seq = value.get("seq").and_then(|seq| seq.as_u64());
+    let message: ControlMessage = match serde_json::from_value(value) {
+        Ok(message) => message,
+        Err(_) => {
+            let error = CommandError::new("invalid_message", "Expected a command, a velocity or a heartbeat".to_string());
+            return Some(ControlEvent::Error { seq, error });
+        }
+    };
+
+    let command = match message {
+        ControlMessage::Heartbeat { heartbeat } => {
+            if heartbeat {
+                watchdog::feed().await;
+            }
+            return None;
+        }
+        ControlMessage::Command(command) => command.validate().map(|_| command),
+        ControlMessage::Velocity(velocity) => velocity.wheel_speeds().map(MotionCommand::Wheels),
+    };
+    let command = match command {
+        Ok(command) => command,
+        Err(error) => return Some(ControlEvent::Error { seq, error }),
+    };
+
+    let line = command.to_string();
+    let result = if *ready {
+        write_line_unchecked(&line).await
+    } else {
+        write_line(&line).await
+    };
+    *ready = result.is_ok();
+
+    Some(match result {
+        Ok(delivery) => ControlEvent::Ack { seq, sent: line, delivery },
+        Err(e) => ControlEvent::Error { seq, error: e.into() },
+    })
+}
+
+// Limits on the per-client options of the IMU stream
+const MAX_DECIMATION: u32 = 1000;
+const MAX_BATCH_MS: u64 = 1000;
+
+// Bytes per sample in the binary encoding
+const BINARY_SAMPLE_LEN: usize = 32;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum ImuEncoding {
+    /// A JSON array of samples, as returned by `/imu/samples`
+    #[default]
+    Json,
+    /// `BINARY_SAMPLE_LEN` bytes per sample, see `encode_binary`
+    Binary,
+}
+
+/// Set in the query string, and replaced by sending new ones (omitted fields
+/// taking their defaults) as a JSON text message
+#[derive(Deserialize, Clone, Copy)]
+pub struct ImuStreamOptions {
+    #[serde(default)]
+    format: ImuEncoding,
+    /// Send one sample out of this many
+    #[serde(default = "default_decimation")]
+    decimation: u32,
+    /// Collect the samples for this long before sending them, 0 sends each one at once
+    #[serde(default = "default_batch_ms")]
+    batch_ms: u64,
+}
+
+fn default_decimation() -> u32 {
+    1
+}
+
+fn default_batch_ms() -> u64 {
+    50
+}
+
+impl ImuStreamOptions {
+    fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_DECIMATION).contains(&self.decimation) {
+            return Err(format!("decimation must be 1 to {}", MAX_DECIMATION));
+        }
+        if self.batch_ms > MAX_BATCH_MS {
+            return Err(format!("batch_ms must be at most {}", MAX_BATCH_MS));
+        }
+        Ok(())
+    }
+}
+
+// Each sample as `t_us` (u64) then accel x, y, z and gyro x, y, z (f32), little-endian
+fn encode_binary(batch: &[ImuSample]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(batch.len() * BINARY_SAMPLE_LEN);
+    for sample in batch {
+        bytes.extend_from_slice(&sample.t_us.to_le_bytes());
+        let (ax, ay, az) = sample.accel;
+        let (gx, gy, gz) = sample.gyro;
+        for value in [ax, ay, az, gx, gy, gz] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    bytes
+}
+
+fn encode_batch(batch: &[ImuSample], format: ImuEncoding) -> Message {
+    match format {
+        ImuEncoding::Json => Message::Text(serde_json::to_string(batch).unwrap()),
+        ImuEncoding::Binary => Message::Binary(encode_binary(batch)),
+    }
+}
+
+/// Streams IMU samples as they are read, e.g. `/imu_ws?format=binary&decimation=2&batch_ms=100`
+pub async fn imu_websocket_handler(ws: WebSocketUpgrade, Query(options): Query<ImuStreamOptions>) -> Response {
+    if let Err(e) = options.validate() {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    ws.on_upgrade(move |socket| async move {
+        let (mut sender, mut receiver) = socket.split();
+        let mut samples = IMU_STREAM.subscribe();
+        let mut options = options;
+        let mut batch = Vec::new();
+        let mut count: u64 = 0;
+        let mut flush = tokio::time::interval(Duration::from_millis(options.batch_ms.max(1)));
+
+        loop {
+            let send_now = tokio::select! {
+                sample = samples.recv() => match sample {
+                    Ok(sample) => {
+                        if count.is_multiple_of(options.decimation as u64) {
+                            batch.push(sample);
+                        }
+                        count += 1;
+                        options.batch_ms == 0
+                    }
+                    // Slow client, skip the samples it missed
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = flush.tick(), if options.batch_ms > 0 => true,
+                message = receiver.next() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        let update = serde_json::from_str::<ImuStreamOptions>(&text)
+                            .map_err(|e| e.to_string())
+                            .and_then(|update| update.validate().map(|_| update));
+                        match update {
+                            Ok(update) => {
+                                options = update;
+                                flush = tokio::time::interval(Duration::from_millis(options.batch_ms.max(1)));
+                            }
+                            Err(e) => {
+                                let error = CommandError::new("invalid_options", e);
+                                if sender.send(Message::Text(serde_json::to_string(&error).unwrap())).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                },
+            };
+
+            if !send_now || batch.is_empty() {
+                continue;
+            }
+            if sender.send(encode_batch(&batch, options.format)).await.is_err() {
+                eprintln!("Error when sending IMU samples: connection closed");
+                break;
+            }
+            batch.clear();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::imu_sample;
+
+    fn options(json: serde_json::Value) -> ImuStreamOptions {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn binary_samples_are_32_bytes_little_endian() {
+        let first = imu_sample(0x0102_0304_0506_0708, (1.0, -2.0, 0.5), (0.25, -0.125, 100.0));
+        let second = imu_sample(42, (0.0, 0.0, 1.0), (0.0, 0.0, 0.0));
+        let bytes = encode_binary(&[first, second]);
+        assert_eq!(bytes.len(), 2 * BINARY_SAMPLE_LEN);
+
+        assert_eq!(bytes[..8], [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+        let floats: Vec<f32> = bytes[8..BINARY_SAMPLE_LEN].chunks(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+        assert_eq!(floats, [1.0, -2.0, 0.5, 0.25, -0.125, 100.0]);
+        // 1.0 as an f32 is 0x3f800000
+        assert_eq!(bytes[8..12], [0x00, 0x00, 0x80, 0x3f]);
+
+        assert_eq!(bytes[BINARY_SAMPLE_LEN..BINARY_SAMPLE_LEN + 8], 42u64.to_le_bytes());
+        assert!(encode_binary(&[]).is_empty());
+    }
+
+    #[test]
+    fn options_default_to_every_sample_in_50_ms_batches() {
+        let defaults = options(serde_json::json!({}));
+        assert!(matches!(defaults.format, ImuEncoding::Json));
+        assert_eq!((defaults.decimation, defaults.batch_ms), (1, 50));
+        assert!(defaults.validate().is_ok());
+        assert!(matches!(options(serde_json::json!({"format": "binary"})).format, ImuEncoding::Binary));
+    }
+
+    #[test]
+    fn validates_decimation_and_batch_bounds() {
+        for (decimation, batch_ms) in [(1, 0), (MAX_DECIMATION, MAX_BATCH_MS)] {
+            assert!(options(serde_json::json!({"decimation": decimation, "batch_ms": batch_ms})).validate().is_ok());
+        }
+        for (decimation, batch_ms) in [(0, 50), (MAX_DECIMATION + 1, 50), (1, MAX_BATCH_MS + 1)] {
+            assert!(options(serde_json::json!({"decimation": decimation, "batch_ms": batch_ms})).validate().is_err());
+        }
+    }
+}
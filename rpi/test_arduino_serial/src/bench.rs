@@ -0,0 +1,92 @@
+use serialport::SerialPort;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::wait_until_ready;
+
+// Probes are numbered unknown commands, which the firmware echoes back as
+// `Unknown command: bench <n>` without doing anything else
+const PROBE_COMMAND: &str = "bench";
+
+// Reads lines until the echo of `probe` arrives, ignoring everything else
+// (telemetry, late echoes of earlier probes). `pending` keeps a partial line.
+fn wait_for_echo(port: &mut Box<dyn SerialPort>, probe: &str, pending: &mut Vec<u8>, deadline: Instant) -> io::Result<bool> {
+    let expected = format!("Unknown command: {}", probe);
+    let mut buffer = [0u8; 64];
+
+    while Instant::now() < deadline {
+        let n = match port.read(&mut buffer) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        pending.extend_from_slice(&buffer[..n]);
+
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            if String::from_utf8_lossy(&line).trim() == expected {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+// Nearest-rank 95th percentile of latencies sorted in increasing order, the same
+// as the backend's `/serial_benchmark`
+fn p95(sorted: &[f64]) -> Option<f64> {
+    (!sorted.is_empty()).then(|| sorted[(sorted.len() * 95).div_ceil(100) - 1])
+}
+
+/// Sends `count` probes one after the other and prints the round-trip times
+pub fn run(mut port: Box<dyn SerialPort>, count: u32, timeout: Duration) -> io::Result<()> {
+    // Probes sent while the Arduino boots would count as lost
+    wait_until_ready(&mut port)?;
+
+    // The read timeout of the port sets how often the deadline is checked
+    port.set_timeout(timeout.min(Duration::from_millis(10)))?;
+
+    let mut pending = Vec::new();
+    let mut latencies = Vec::new();
+    for i in 0..count {
+        let probe = format!("{} {}", PROBE_COMMAND, i);
+        let start = Instant::now();
+        port.write_all(format!("{}\n", probe).as_bytes())?;
+
+        if wait_for_echo(&mut port, &probe, &mut pending, start + timeout)? {
+            latencies.push(start.elapsed().as_micros() as f64 / 1000.0);
+        }
+    }
+
+    latencies.sort_by(f64::total_cmp);
+    let answered = latencies.len();
+    println!("Sent {} probes, {} lost", count, count as usize - answered);
+    if let Some(p95) = p95(&latencies) {
+        let mean = latencies.iter().sum::<f64>() / answered as f64;
+        println!(
+            "Round trip: min {:.3} ms, mean {:.3} ms, p95 {:.3} ms, max {:.3} ms",
+            latencies[0],
+            mean,
+            p95,
+            latencies[answered - 1],
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(n: u32) -> Vec<f64> {
+        (1..=n).map(f64::from).collect()
+    }
+
+    #[test]
+    fn p95_is_the_nearest_rank() {
+        assert_eq!(p95(&[]), None);
+        assert_eq!(p95(&[4.2]), Some(4.2));
+        assert_eq!(p95(&sorted(20)), Some(19.0));
+        assert_eq!(p95(&sorted(21)), Some(20.0));
+    }
+}
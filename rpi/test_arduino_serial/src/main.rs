@@ -1,65 +1,177 @@
-use serialport::{SerialPort, DataBits, FlowControl, Parity, StopBits};
+use clap::{Parser, Subcommand};
+use serialport::{SerialPort, SerialPortType, DataBits, FlowControl, Parity, StopBits};
 use std::io::{self, Write};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+mod bench;
+mod monitor;
+mod repl;
+mod script;
+
+// The Arduino resets when the port is opened and ignores input while its
+// bootloader runs, so it is pinged until it answers
+const READY_TIMEOUT: Duration = Duration::from_secs(3);
+const PING_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Talks to the carbot Arduino (or the backend's virtual Arduino) over a serial port
+#[derive(Parser)]
+struct Cli {
+    /// Serial port, e.g. /dev/ttyUSB0 or the /dev/pts/N of a virtual Arduino
+    #[arg(short, long, default_value = "/dev/ttyUSB0", global = true)]
+    port: String,
+
+    /// Baud rate, as set by `Serial.begin` in the firmware
+    #[arg(short, long, default_value_t = 115200, global = true)]
+    baud: u32,
+
+    /// How long to wait for the Arduino's response after a command
+    #[arg(long, default_value_t = 100, global = true)]
+    timeout_ms: u64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the available serial ports
+    List,
+    /// Type commands interactively (the default)
+    Repl,
+    /// Send the commands of a file, one per line. `delay <ms>` waits and `#` starts a comment.
+    Run {
+        file: PathBuf,
+        /// Wait this long after each command, on top of the `delay` lines
+        #[arg(long, default_value_t = 0)]
+        delay_ms: u64,
+    },
+    /// Print everything the Arduino sends, with timestamps
+    Monitor {
+        /// Show the received bytes as a hex dump
+        #[arg(long)]
+        hex: bool,
+    },
+    /// Measure the round trip to the firmware with probes it echoes back
+    Bench {
+        #[arg(short = 'n', long, default_value_t = 100)]
+        count: u32,
+        /// How long to wait for each echo before counting the probe as lost
+        #[arg(long, default_value_t = 500)]
+        probe_timeout_ms: u64,
+    },
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Serial port configuration
-    let port_name = "/dev/ttyUSB0";
-    let baud_rate = 115200;
+    let cli = Cli::parse();
+    let timeout = Duration::from_millis(cli.timeout_ms);
+
+    match cli.command.unwrap_or(Command::Repl) {
+        Command::List => list_ports()?,
+        Command::Repl => repl::run(open_port(&cli.port, cli.baud, timeout)?)?,
+        Command::Run { file, delay_ms } => {
+            script::run(open_port(&cli.port, cli.baud, timeout)?, &file, Duration::from_millis(delay_ms))?
+        }
+        Command::Monitor { hex } => monitor::run(open_port(&cli.port, cli.baud, timeout)?, hex)?,
+        Command::Bench { count, probe_timeout_ms } => {
+            bench::run(open_port(&cli.port, cli.baud, timeout)?, count, Duration::from_millis(probe_timeout_ms))?
+        }
+    }
+    Ok(())
+}
 
-    // Open the serial port
-    let mut port = serialport::new(port_name, baud_rate)
+fn open_port(port_name: &str, baud_rate: u32, timeout: Duration) -> serialport::Result<Box<dyn SerialPort>> {
+    let port = serialport::new(port_name, baud_rate)
         .data_bits(DataBits::Eight)
         .flow_control(FlowControl::None)
         .parity(Parity::None)
         .stop_bits(StopBits::One)
-        .timeout(Duration::from_millis(100)) // Short timeout for non-blocking reads
+        .timeout(timeout) // Short timeout for non-blocking reads
         .open()?;
+    println!("Serial port {} opened at {} baud", port_name, baud_rate);
+    Ok(port)
+}
 
-    println!("Serial port opened. Type a command (e.g., 'forward'):");
+fn list_ports() -> serialport::Result<()> {
+    let ports = serialport::available_ports()?;
+    if ports.is_empty() {
+        println!("No serial ports found");
+    }
+    for port in ports {
+        match port.port_type {
+            SerialPortType::UsbPort(usb) => println!(
+                "{}  USB {:04x}:{:04x} {} {}",
+                port.port_name,
+                usb.vid,
+                usb.pid,
+                usb.manufacturer.unwrap_or_default(),
+                usb.product.unwrap_or_default(),
+            ),
+            SerialPortType::BluetoothPort => println!("{}  Bluetooth", port.port_name),
+            SerialPortType::PciPort => println!("{}  PCI", port.port_name),
+            SerialPortType::Unknown => println!("{}", port.port_name),
+        }
+    }
+    Ok(())
+}
 
-    // Loop to send commands
+// Reads until the read times out, returning whether anything arrived
+fn drain(port: &mut Box<dyn SerialPort>) -> io::Result<bool> {
+    let mut buffer = [0u8; 64];
+    let mut received = false;
     loop {
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        let command = input.trim();
-
-        // Exit if the user types "exit"
-        if command == "exit" {
-            break;
+        match port.read(&mut buffer) {
+            Ok(n) if n > 0 => received = true,
+            Ok(_) => return Ok(received),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(received),
+            Err(e) => return Err(e),
         }
+    }
+}
 
-        // Send the command to the Arduino
-        port.write_all(command.as_bytes())?;
+/// Waits until the firmware answers, so the first command isn't lost while the
+/// Arduino boots. An empty line is a ping, which it answers with `Unknown command: `.
+pub fn wait_until_ready(port: &mut Box<dyn SerialPort>) -> io::Result<()> {
+    let start = Instant::now();
+    while start.elapsed() < READY_TIMEOUT {
         port.write_all(b"\n")?;
-
-        // Read the Arduino's response (non-blocking)
-        let mut response = String::new();
-        let mut buffer = [0u8; 64];
-        loop {
-            match port.read(&mut buffer) {
-                Ok(n) if n > 0 => {
-                    // Data received, append to response
-                    response.push_str(&String::from_utf8_lossy(&buffer[..n]));
-                }
-                Ok(_) => {
-                    // Timeout occurred, no data received
-                    break;
-                }
-                Err(e) => {
-                    // Handle other errors (e.g., port disconnected)
-                    eprintln!("Error reading from serial port: {}", e);
-                    break;
-                }
+        let ping_sent = Instant::now();
+        while ping_sent.elapsed() < PING_INTERVAL {
+            // The answer is skipped, along with those to earlier pings
+            if drain(port)? {
+                return Ok(());
             }
         }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "The Arduino didn't answer, is the carbot firmware running?"))
+}
+
+/// Sends a command line and prints what the Arduino answers until the read times out
+pub fn send_command(port: &mut Box<dyn SerialPort>, command: &str) -> io::Result<()> {
+    port.write_all(command.as_bytes())?;
+    port.write_all(b"\n")?;
 
-        if !response.is_empty() {
-            println!("Arduino response: {}", response);
-        } else {
-            println!("No response (timeout). Command sent successfully.");
+    // Read the Arduino's response (non-blocking)
+    let mut response = String::new();
+    let mut buffer = [0u8; 64];
+    loop {
+        match port.read(&mut buffer) {
+            Ok(n) if n > 0 => {
+                // Data received, append to response
+                response.push_str(&String::from_utf8_lossy(&buffer[..n]));
+            }
+            Ok(_) => break,
+            // Timeout occurred, no more data
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+            // Other errors (e.g., port disconnected)
+            Err(e) => return Err(e),
         }
     }
 
+    if !response.is_empty() {
+        println!("Arduino response: {}", response.trim_end());
+    } else {
+        println!("No response (timeout). Command sent successfully.");
+    }
     Ok(())
 }
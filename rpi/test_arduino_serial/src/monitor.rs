@@ -0,0 +1,51 @@
+use serialport::SerialPort;
+use std::io;
+use std::time::Instant;
+
+// Bytes per row of the hex dump
+const HEX_ROW: usize = 16;
+
+/// Prints what the Arduino sends until the port closes or Ctrl-C, each line
+/// (or chunk of bytes with `hex`) prefixed by the seconds since the start
+pub fn run(mut port: Box<dyn SerialPort>, hex: bool) -> io::Result<()> {
+    let start = Instant::now();
+    let mut buffer = [0u8; 256];
+    let mut line = Vec::new();
+
+    println!("Monitoring, press Ctrl-C to stop");
+    loop {
+        let n = match port.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        let timestamp = start.elapsed().as_secs_f64();
+        let bytes = &buffer[..n];
+
+        if hex {
+            print_hex_dump(timestamp, bytes);
+            continue;
+        }
+
+        // Print whole lines, the Arduino's output arrives in arbitrary pieces
+        for &byte in bytes {
+            if byte == b'\n' {
+                println!("[{:10.3}] {}", timestamp, String::from_utf8_lossy(&line).trim_end_matches('\r'));
+                line.clear();
+            } else {
+                line.push(byte);
+            }
+        }
+    }
+}
+
+fn print_hex_dump(timestamp: f64, bytes: &[u8]) {
+    for (i, row) in bytes.chunks(HEX_ROW).enumerate() {
+        let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = row.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        // Only the first row of a chunk gets the timestamp
+        let prefix = if i == 0 { format!("[{:10.3}]", timestamp) } else { " ".repeat(12) };
+        println!("{} {:<width$}  |{}|", prefix, hex.join(" "), ascii, width = HEX_ROW * 3 - 1);
+    }
+}
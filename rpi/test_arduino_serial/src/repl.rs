@@ -0,0 +1,51 @@
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use serialport::SerialPort;
+use std::path::PathBuf;
+
+use crate::send_command;
+
+// Kept across sessions, like a shell history
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".test_arduino_serial_history"))
+}
+
+/// Reads commands with line editing and history (arrow keys, Ctrl-R) until `exit` or Ctrl-D
+pub fn run(mut port: Box<dyn SerialPort>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // No history yet on the first run
+        let _ = editor.load_history(path);
+    }
+
+    println!("Type a command (e.g., 'forward'), 'exit' to quit:");
+
+    loop {
+        let command = match editor.readline("> ") {
+            Ok(line) => line.trim().to_string(),
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if command.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(&command)?;
+
+        // Exit if the user types "exit"
+        if command == "exit" || command == "quit" {
+            break;
+        }
+
+        if let Err(e) = send_command(&mut port, &command) {
+            eprintln!("Error talking to the serial port: {}", e);
+        }
+    }
+
+    if let Some(path) = &history
+        && let Err(e) = editor.save_history(path)
+    {
+        eprintln!("Failed to save the history to {}: {}", path.display(), e);
+    }
+    Ok(())
+}
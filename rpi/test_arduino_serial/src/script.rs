@@ -0,0 +1,89 @@
+use serialport::SerialPort;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{send_command, wait_until_ready};
+
+/// One line of a command file
+#[derive(Debug, PartialEq)]
+enum Step<'a> {
+    Command(&'a str),
+    Delay(Duration),
+}
+
+// Blank lines and comments give `None`
+fn parse_line(line: &str) -> Result<Option<Step<'_>>, String> {
+    let line = line.split('#').next().unwrap_or("").trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    match line.strip_prefix("delay ") {
+        Some(ms) => ms
+            .trim()
+            .parse()
+            .map(|ms| Some(Step::Delay(Duration::from_millis(ms))))
+            .map_err(|_| format!("Invalid delay '{}', expected milliseconds", ms.trim())),
+        None => Ok(Some(Step::Command(line))),
+    }
+}
+
+/// Sends the commands of a file, e.g.
+///
+/// ```text
+/// speed 800
+/// forward
+/// delay 2000   # drive for 2 s
+/// stop
+/// ```
+pub fn run(mut port: Box<dyn SerialPort>, file: &Path, delay: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+
+    // Check the whole file first rather than stopping halfway with the robot moving
+    let mut steps = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(step)) => steps.push((i + 1, step)),
+            Ok(None) => {}
+            Err(e) => return Err(format!("{}:{}: {}", file.display(), i + 1, e).into()),
+        }
+    }
+
+    wait_until_ready(&mut port)?;
+    for (line_number, step) in steps {
+        match step {
+            Step::Command(command) => {
+                println!("[line {}] {}", line_number, command);
+                send_command(&mut port, command)?;
+                std::thread::sleep(delay);
+            }
+            Step::Delay(duration) => std::thread::sleep(duration),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_blank_lines_and_comments() {
+        assert_eq!(parse_line(""), Ok(None));
+        assert_eq!(parse_line("   "), Ok(None));
+        assert_eq!(parse_line("# stop here"), Ok(None));
+    }
+
+    #[test]
+    fn parses_commands_and_delays() {
+        assert_eq!(parse_line("  speed 800 "), Ok(Some(Step::Command("speed 800"))));
+        assert_eq!(parse_line("delay 500"), Ok(Some(Step::Delay(Duration::from_millis(500)))));
+        assert_eq!(parse_line("delay 2000   # drive for 2 s"), Ok(Some(Step::Delay(Duration::from_millis(2000)))));
+        assert_eq!(parse_line("forward # full speed"), Ok(Some(Step::Command("forward"))));
+    }
+
+    #[test]
+    fn rejects_invalid_delays() {
+        assert!(parse_line("delay soon").is_err());
+        assert!(parse_line("delay -5").is_err());
+    }
+}